@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use crate::error::TapLockError;
+use crate::oidc::{self, OidcOAuth2Client, OidcProvider};
+use crate::options::RuntimeOptions;
+
+// Auth0 only accepts custom claims with a namespace, usually a URL
+// such as `https://example.com/roles`. The claims of the configured
+// namespace are also copied without it (`roles`), unless a claim of
+// that name already exists. Claims nested below the namespace are left
+// alone
+fn normalize_claims(namespace: &str, claims: &mut serde_json::Value) {
+    let Some(claims) = claims.as_object_mut() else {
+        return;
+    };
+    let namespace = format!("{}/", namespace.trim_end_matches('/'));
+    let namespaced: Vec<(String, serde_json::Value)> = claims
+        .iter()
+        .filter_map(|(name, value)| {
+            let short = name.strip_prefix(&namespace)?;
+            if short.is_empty() || short.contains('/') {
+                return None;
+            }
+            Some((short.to_string(), value.clone()))
+        })
+        .collect();
+    for (name, value) in namespaced {
+        claims.entry(name).or_insert(value);
+    }
+}
+
+fn provider(domain: &str, audience: Option<&str>, namespace: Option<&str>) -> OidcProvider {
+    let base_url = oidc::domain_url(domain);
+    let namespace = namespace.map(str::to_string);
+    OidcProvider {
+        auth_url: format!("{base_url}/authorize"),
+        token_url: format!("{base_url}/oauth/token"),
+        jwks_url: format!("{base_url}/.well-known/jwks.json"),
+        // The issuer of Auth0 tokens ends with a slash
+        issuer: format!("{base_url}/"),
+        default_scopes: &["openid", "email", "profile", "offline_access"],
+        // Without an audience Auth0 issues opaque access tokens that
+        // only work with its userinfo endpoint
+        auth_params: audience
+            .map(|audience| vec![("audience".to_string(), audience.to_string())])
+            .unwrap_or_default(),
+        normalize_claims: Arc::new(move |claims| {
+            if let Some(namespace) = &namespace {
+                normalize_claims(namespace, claims);
+            }
+        }),
+        sign_client_secret: None,
+    }
+}
+
+pub async fn build_oauth2_state_auth0(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    domain: &str,
+    audience: Option<&str>,
+    use_refresh_token: bool,
+    options: &RuntimeOptions,
+) -> std::result::Result<OidcOAuth2Client, TapLockError> {
+    oidc::build_oauth2_state_oidc(
+        client_id,
+        client_secret,
+        app_url,
+        provider(domain, audience, options.claims_namespace.as_deref()),
+        use_refresh_token,
+        options,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn derives_the_endpoints_from_the_domain() {
+        let provider = provider("example.eu.auth0.com", None, None);
+        assert_eq!(provider.issuer, "https://example.eu.auth0.com/");
+        assert_eq!(provider.auth_url, "https://example.eu.auth0.com/authorize");
+        assert_eq!(
+            provider.token_url,
+            "https://example.eu.auth0.com/oauth/token"
+        );
+        assert_eq!(
+            provider.jwks_url,
+            "https://example.eu.auth0.com/.well-known/jwks.json"
+        );
+        assert!(provider.auth_params.is_empty());
+    }
+
+    #[test]
+    fn requests_the_audience() {
+        let provider = provider(
+            "example.eu.auth0.com",
+            Some("https://api.example.com"),
+            None,
+        );
+        assert_eq!(
+            provider.auth_params,
+            vec![(
+                "audience".to_string(),
+                "https://api.example.com".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn copies_the_claims_of_the_namespace() {
+        let mut claims = json!({
+            "https://example.com/roles": ["admin"],
+            "https://example.com/groups/": ["team"],
+            "https://other.example.com/roles": ["owner"],
+            "https://example.com/app/teams": ["nested"],
+            "https://example.com/": "namespace only",
+        });
+        normalize_claims("https://example.com/", &mut claims);
+        assert_eq!(claims["roles"], json!(["admin"]));
+        // Other namespaces, nested claims and the bare namespace are not
+        // copied
+        assert!(claims.get("groups").is_none());
+        assert!(claims.get("teams").is_none());
+        assert!(claims.get("example.com").is_none());
+        assert_eq!(claims.as_object().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn never_overwrites_claims() {
+        let mut claims = json!({
+            "https://example.com/email": "other@example.com",
+            "https://example.com/sub": "other",
+            "email": "user@example.com",
+            "sub": "auth0|1",
+        });
+        normalize_claims("https://example.com", &mut claims);
+        assert_eq!(claims["email"], json!("user@example.com"));
+        assert_eq!(claims["sub"], json!("auth0|1"));
+    }
+
+    #[test]
+    fn copies_nothing_without_a_namespace() {
+        let mut claims = json!({ "https://example.com/roles": ["admin"] });
+        (provider("example.eu.auth0.com", None, None).normalize_claims)(&mut claims);
+        assert!(claims.get("roles").is_none());
+
+        (provider("example.eu.auth0.com", None, Some("https://example.com")).normalize_claims)(
+            &mut claims,
+        );
+        assert_eq!(claims["roles"], json!(["admin"]));
+    }
+}
@@ -0,0 +1,67 @@
+use extendr_api::prelude::*;
+use oauth2::url::form_urlencoded;
+
+/// The parameters a provider sends back to the callback, in the query
+/// string or, with `response_mode=form_post`, in the body of a POST.
+#[derive(Debug, Default)]
+pub(crate) struct CallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+    /// The user, which Apple sends on the first login only
+    pub user: Option<String>,
+}
+
+impl CallbackParams {
+    /// Parses an `application/x-www-form-urlencoded` string. Only the
+    /// first value of a repeated field is kept, other fields are
+    /// ignored.
+    pub fn parse(input: &str) -> Self {
+        let mut params = CallbackParams::default();
+        for (name, value) in form_urlencoded::parse(input.as_bytes()) {
+            let field = match name.as_ref() {
+                "code" => &mut params.code,
+                "state" => &mut params.state,
+                "error" => &mut params.error,
+                "error_description" => &mut params.error_description,
+                "user" => &mut params.user,
+                _ => continue,
+            };
+            field.get_or_insert_with(|| value.into_owned());
+        }
+        params
+    }
+}
+
+impl IntoRobj for CallbackParams {
+    fn into_robj(self) -> Robj {
+        list!(
+            code = self.code,
+            state = self.state,
+            error = self.error,
+            error_description = self.error_description,
+            user = self.user
+        )
+        .into()
+    }
+}
+
+/// @title Parse a callback
+/// @description Parses the parameters sent back by the provider, from
+///   a query string or an `application/x-www-form-urlencoded` body
+///
+/// @param body The query string (without `?`) or the body of the request
+///
+/// @return A list with the `code`, `state`, `error`,
+///   `error_description` and `user` fields, `NULL` when absent
+/// @keywords internal
+#[extendr]
+fn parse_callback(body: Option<&str>) -> Robj {
+    CallbackParams::parse(body.unwrap_or("")).into_robj()
+}
+
+extendr_module! {
+    mod callback;
+    fn parse_callback;
+}
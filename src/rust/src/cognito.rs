@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use crate::error::TapLockError;
+use crate::oidc::{self, OidcOAuth2Client, OidcProvider};
+use crate::options::RuntimeOptions;
+
+// Cognito names the groups of a user `cognito:groups`, and leaves the
+// claim out when they belong to none. They are copied to `groups`, as
+// is `cognito:username` to `preferred_username`
+fn normalize_claims(claims: &mut serde_json::Value) {
+    let Some(claims) = claims.as_object_mut() else {
+        return;
+    };
+    let groups = claims
+        .get("cognito:groups")
+        .cloned()
+        .unwrap_or_else(|| serde_json::Value::Array(Vec::new()));
+    claims.entry("groups").or_insert(groups);
+    if let Some(username) = claims.get("cognito:username").cloned() {
+        claims.entry("preferred_username").or_insert(username);
+    }
+}
+
+fn provider(domain: &str, user_pool_id: &str) -> std::result::Result<OidcProvider, TapLockError> {
+    // User pool ids start with their region, as in `eu-west-1_AbCdEf123`
+    let region = match user_pool_id.split_once('_') {
+        Some((region, _)) if !region.is_empty() => region,
+        _ => {
+            return Err(TapLockError::new(format!(
+                "Invalid user pool id `{user_pool_id}`"
+            )))
+        }
+    };
+    let issuer = format!("https://cognito-idp.{region}.amazonaws.com/{user_pool_id}");
+    let base_url = oidc::domain_url(domain);
+    Ok(OidcProvider {
+        auth_url: format!("{base_url}/oauth2/authorize"),
+        token_url: format!("{base_url}/oauth2/token"),
+        jwks_url: format!("{issuer}/.well-known/jwks.json"),
+        issuer,
+        // Cognito always issues refresh tokens and rejects the
+        // `offline_access` scope
+        default_scopes: &["openid", "email", "profile"],
+        auth_params: Vec::new(),
+        normalize_claims: Arc::new(normalize_claims),
+        sign_client_secret: None,
+    })
+}
+
+pub async fn build_oauth2_state_cognito(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    domain: &str,
+    user_pool_id: &str,
+    use_refresh_token: bool,
+    options: &RuntimeOptions,
+) -> std::result::Result<OidcOAuth2Client, TapLockError> {
+    oidc::build_oauth2_state_oidc(
+        client_id,
+        client_secret,
+        app_url,
+        provider(domain, user_pool_id)?,
+        use_refresh_token,
+        options,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn derives_the_issuer_from_the_user_pool() {
+        let provider = provider("auth.example.com", "eu-west-1_AbCdEf123").unwrap();
+        assert_eq!(
+            provider.issuer,
+            "https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_AbCdEf123"
+        );
+        assert_eq!(
+            provider.jwks_url,
+            "https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_AbCdEf123/.well-known/jwks.json"
+        );
+        assert_eq!(
+            provider.auth_url,
+            "https://auth.example.com/oauth2/authorize"
+        );
+        assert_eq!(provider.token_url, "https://auth.example.com/oauth2/token");
+    }
+
+    #[test]
+    fn rejects_invalid_user_pools() {
+        assert!(provider("auth.example.com", "AbCdEf123").is_err());
+        assert!(provider("auth.example.com", "_AbCdEf123").is_err());
+    }
+
+    #[test]
+    fn copies_groups_and_username() {
+        let mut claims = json!({
+            "cognito:groups": ["admins"],
+            "cognito:username": "user",
+        });
+        normalize_claims(&mut claims);
+        assert_eq!(claims["groups"], json!(["admins"]));
+        assert_eq!(claims["preferred_username"], json!("user"));
+
+        let mut claims = json!({ "preferred_username": "name" });
+        normalize_claims(&mut claims);
+        assert_eq!(claims["groups"], json!([]));
+        assert_eq!(claims["preferred_username"], json!("name"));
+    }
+}
@@ -1,22 +1,377 @@
 use extendr_api::prelude::*;
+use std::borrow::Cow;
 
-/// @title Parse cookies
-/// @description Parses cookies from a string
-///
-/// @param x A string containing the cookies
-///
-/// @return A list containing the cookies
-/// @keywords internal
-#[extendr]
-fn parse_cookies(cookie_string: Option<&str>) -> List {
-    // Use unwrap_or to default to an empty string and trim whitespace.
-    let input = cookie_string.unwrap_or("").trim();
-    if input.is_empty() {
-        return List::new(0);
+use crate::error::TapLockError;
+use crate::options::RuntimeOptions;
+use crate::seal::KeyRing;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn parse(value: &str) -> std::result::Result<Self, TapLockError> {
+        match value.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(TapLockError::new(format!(
+                "Invalid SameSite value `{value}`, expected `Strict`, `Lax` or `None`"
+            ))),
+        }
     }
 
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CookiePrefix {
+    NoPrefix,
+    /// `__Host-`: Secure, `Path=/` and no `Domain`
+    Host,
+    /// `__Secure-`: Secure
+    Secure,
+}
+
+impl CookiePrefix {
+    pub fn parse(value: &str) -> std::result::Result<Self, TapLockError> {
+        match value {
+            "" => Ok(CookiePrefix::NoPrefix),
+            "__Host-" => Ok(CookiePrefix::Host),
+            "__Secure-" => Ok(CookiePrefix::Secure),
+            _ => Err(TapLockError::new(format!(
+                "Invalid cookie prefix `{value}`, expected `__Host-` or `__Secure-`"
+            ))),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CookiePrefix::NoPrefix => "",
+            CookiePrefix::Host => "__Host-",
+            CookiePrefix::Secure => "__Secure-",
+        }
+    }
+}
+
+/// Cookie attributes as configured in `runtime_options()`.
+#[derive(Debug, Clone)]
+pub(crate) struct CookieOptions {
+    /// `None` sets `Secure` only when the app is served over HTTPS
+    pub secure: Option<bool>,
+    pub prefix: CookiePrefix,
+    pub domain: Option<String>,
+    pub path: String,
+    pub same_site: SameSite,
+    /// Seal (encrypt and authenticate) the values when set
+    pub keys: Option<KeyRing>,
+}
+
+impl Default for CookieOptions {
+    fn default() -> Self {
+        Self {
+            secure: None,
+            prefix: CookiePrefix::NoPrefix,
+            domain: None,
+            path: "/".to_string(),
+            same_site: SameSite::Lax,
+            keys: None,
+        }
+    }
+}
+
+impl CookieOptions {
+    /// Checks the options against the app URL and the constraints of
+    /// the cookie prefixes.
+    pub fn resolve(&self, app_url: &str) -> std::result::Result<CookieBuilder, TapLockError> {
+        let is_https = app_url.to_ascii_lowercase().starts_with("https://");
+        let secure = self.secure.unwrap_or(is_https);
+
+        if !secure && self.prefix != CookiePrefix::NoPrefix {
+            return Err(TapLockError::new(format!(
+                "Cookies with the `{}` prefix must be `Secure`",
+                self.prefix.as_str()
+            )));
+        }
+        if !secure && self.same_site == SameSite::None {
+            return Err(TapLockError::new(
+                "Cookies with `SameSite=None` must be `Secure`",
+            ));
+        }
+        if self.prefix == CookiePrefix::Host && (self.domain.is_some() || self.path != "/") {
+            return Err(TapLockError::new(
+                "Cookies with the `__Host-` prefix must use `Path=/` and no `Domain`",
+            ));
+        }
+
+        Ok(CookieBuilder {
+            secure,
+            prefix: self.prefix,
+            domain: self.domain.clone(),
+            path: self.path.clone(),
+            same_site: self.same_site,
+            keys: self.keys.clone(),
+        })
+    }
+}
+
+/// Builds `Set-Cookie` header values with the attributes of a runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct CookieBuilder {
+    secure: bool,
+    prefix: CookiePrefix,
+    domain: Option<String>,
+    path: String,
+    same_site: SameSite,
+    keys: Option<KeyRing>,
+}
+
+// Browsers reject cookies larger than 4096 bytes, counting the name,
+// the value and (for some of them) the attributes.
+const MAX_COOKIE_SIZE: usize = 4096;
+const CHUNK_SEPARATOR: char = '.';
+
+// The cookies built by tapLock, the only ones sealed with `cookie_keys`.
+// Other cookies of the app may share the prefix (or lack of one).
+const OWN_COOKIES: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "session",
+    "session_id",
+    "login_state",
+];
+
+// RFC 6265 `token`: visible ASCII without separators
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
+}
+
+impl CookieBuilder {
+    pub fn name(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix.as_str())
+    }
+
+    /// The same builder for cookies that must come back with the
+    /// provider's redirect, which is a cross-site `POST` with
+    /// `response_mode=form_post`. `SameSite=None` requires `Secure`, so
+    /// plain HTTP apps get `SameSite=Lax`.
+    pub fn cross_site(&self) -> Self {
+        let same_site = if self.secure {
+            SameSite::None
+        } else {
+            SameSite::Lax
+        };
+        Self {
+            same_site,
+            ..self.clone()
+        }
+    }
+
+    fn attributes(&self, max_age: Option<u64>) -> String {
+        let mut attributes = format!("; Path={}", self.path);
+        if let Some(domain) = &self.domain {
+            attributes.push_str(&format!("; Domain={domain}"));
+        }
+        if let Some(max_age) = max_age {
+            attributes.push_str(&format!("; Max-Age={max_age}"));
+        }
+        attributes.push_str(&format!("; SameSite={}", self.same_site.as_str()));
+        if self.secure {
+            attributes.push_str("; Secure");
+        }
+        attributes.push_str("; HttpOnly");
+        attributes
+    }
+
+    fn clear_one(&self, name: &str) -> String {
+        format!("{}=; {}", self.name(name), &self.attributes(Some(0))[2..])
+    }
+
+    /// Builds the `Set-Cookie` header values storing `value` under
+    /// `name`. The value is sealed if keys are configured, and
+    /// percent-encoded otherwise, so any string can be stored and the
+    /// result only contains RFC 6265 `cookie-octet`s.
+    ///
+    /// Values that would make the cookie larger than browsers accept are
+    /// split into `name.0`, `name.1`, ... `existing` are the names of the
+    /// cookies sent with the request, so that leftovers of a previous
+    /// value (e.g. a chunk that is no longer needed) are cleared.
+    pub fn build(
+        &self,
+        name: &str,
+        value: &str,
+        max_age: Option<u64>,
+        existing: &[&str],
+    ) -> std::result::Result<Vec<String>, TapLockError> {
+        if !is_valid_name(name) {
+            return Err(TapLockError::new(format!("Invalid cookie name `{name}`")));
+        }
+        let encoded = match &self.keys {
+            Some(keys) => Cow::Owned(keys.seal(name, value)?),
+            None => urlencoding::encode(value),
+        };
+        let attributes = self.attributes(max_age);
+        let full_name = self.name(name);
+
+        let mut cookies = Vec::new();
+        let mut chunk_count = 0;
+        if full_name.len() + 1 + encoded.len() + attributes.len() <= MAX_COOKIE_SIZE {
+            cookies.push(format!("{full_name}={encoded}{attributes}"));
+        } else {
+            // Room for the `.N=` suffix with up to three digits
+            let chunk_size = MAX_COOKIE_SIZE
+                .checked_sub(full_name.len() + 5 + attributes.len())
+                .filter(|size| *size > 3)
+                .ok_or_else(|| TapLockError::new("Cookie attributes are too long"))?;
+            for (index, chunk) in split_encoded(&encoded, chunk_size).into_iter().enumerate() {
+                cookies.push(format!(
+                    "{full_name}{CHUNK_SEPARATOR}{index}={chunk}{attributes}"
+                ));
+                chunk_count += 1;
+            }
+        }
+
+        for existing in existing {
+            let stale = match split_chunk(existing) {
+                Some((base, index)) => base == name && index >= chunk_count,
+                None => *existing == name && chunk_count > 0,
+            };
+            if stale {
+                cookies.push(self.clear_one(existing));
+            }
+        }
+
+        Ok(cookies)
+    }
+
+    /// Clears the cookie `name` and any of its chunks in `existing`.
+    pub fn clear(
+        &self,
+        name: &str,
+        existing: &[&str],
+    ) -> std::result::Result<Vec<String>, TapLockError> {
+        if !is_valid_name(name) {
+            return Err(TapLockError::new(format!("Invalid cookie name `{name}`")));
+        }
+        let mut cookies = vec![self.clear_one(name)];
+        cookies.extend(
+            existing
+                .iter()
+                .filter(|existing| matches!(split_chunk(existing), Some((base, _)) if base == name))
+                .map(|existing| self.clear_one(existing)),
+        );
+        Ok(cookies)
+    }
+
+    /// The names of the cookies of this runtime sent with a request,
+    /// without the prefix and before reassembling chunks.
+    pub fn names<'a>(&self, cookie_string: &'a str) -> Vec<&'a str> {
+        raw_pairs(cookie_string)
+            .into_iter()
+            .filter_map(|(key, _)| key.strip_prefix(self.prefix.as_str()))
+            .collect()
+    }
+
+    /// Parses a `Cookie` header keeping only the cookies carrying the
+    /// configured prefix, with the prefix removed. With keys, the
+    /// cookies of tapLock are opened and those that do not authenticate
+    /// are reported as tampered, other cookies are only decoded.
+    pub fn parse<'a>(&self, cookie_string: &'a str) -> ParsedCookies<'a> {
+        let mut parsed = ParsedCookies::default();
+        for (key, value) in reassemble(&raw_pairs(cookie_string)) {
+            let Some(key) = key.strip_prefix(self.prefix.as_str()) else {
+                continue;
+            };
+            let value = match &self.keys {
+                Some(keys) if OWN_COOKIES.contains(&key) => match keys.open(key, &value) {
+                    Ok(value) => Some(value),
+                    Err(TapLockError::CookieTampered(_)) => {
+                        parsed.tampered.push(key);
+                        None
+                    }
+                    Err(_) => None,
+                },
+                _ => decode(&value),
+            };
+            if let Some(value) = value {
+                parsed.values.push((key, value));
+            }
+        }
+        parsed
+    }
+}
+
+/// The cookies of a request, as read by [`CookieBuilder::parse`].
+#[derive(Debug, Default)]
+pub(crate) struct ParsedCookies<'a> {
+    pub values: Vec<(&'a str, String)>,
+    /// Sealed cookies that did not authenticate: modified, forged or
+    /// sealed with a key that is no longer configured
+    pub tampered: Vec<&'a str>,
+}
+
+impl ParsedCookies<'_> {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The cookies as a named list, with the names of the tampered ones
+    /// in its `tampered` attribute.
+    pub fn into_list(self) -> Result<Robj> {
+        let tampered: Vec<String> = self.tampered.iter().map(|name| name.to_string()).collect();
+        let mut list = Robj::from(pairs_to_list(self.values));
+        if !tampered.is_empty() {
+            list.set_attrib("tampered", tampered)?;
+        }
+        Ok(list)
+    }
+}
+
+// Splits a percent-encoded string in pieces of at most `size` bytes
+// without cutting through a `%XX` escape.
+fn split_encoded(encoded: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = encoded;
+    while rest.len() > size {
+        let mut cut = size;
+        if let Some(pos) = rest[cut - 2..cut].find('%') {
+            cut = cut - 2 + pos;
+        }
+        let (chunk, tail) = rest.split_at(cut);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+    chunks
+}
+
+// `name.3` -> `("name", 3)`
+fn split_chunk(key: &str) -> Option<(&str, usize)> {
+    let (base, index) = key.rsplit_once(CHUNK_SEPARATOR)?;
+    if base.is_empty() || index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((base, index.parse().ok()?))
+}
+
+fn raw_pairs(input: &str) -> Vec<(&str, &str)> {
     // Split the cookie string on ';' and process each key=value segment.
-    let values = input
+    input
         .split(';')
         .filter_map(|segment| {
             let trimmed_segment = segment.trim();
@@ -29,16 +384,221 @@ fn parse_cookies(cookie_string: Option<&str>) -> List {
             if key.is_empty() {
                 return None;
             }
-            // Trim the raw value and attempt URL decoding.
-            let decoded_value = urlencoding::decode(raw_value.trim()).ok()?;
-            Some((key, Robj::from(decoded_value.as_ref())))
+            Some((key, raw_value.trim()))
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+// Joins `name.0`, `name.1`, ... back into `name`. Chunks are ignored if
+// a cookie with the plain name exists, or if they do not start at 0.
+fn reassemble<'a>(pairs: &[(&'a str, &'a str)]) -> Vec<(&'a str, Cow<'a, str>)> {
+    let mut cookies = Vec::new();
+    for &(key, value) in pairs {
+        let Some((base, index)) = split_chunk(key) else {
+            cookies.push((key, Cow::Borrowed(value)));
+            continue;
+        };
+        if index != 0 || pairs.iter().any(|(k, _)| *k == base) {
+            continue;
+        }
+        let mut joined = String::new();
+        for index in 0.. {
+            match pairs
+                .iter()
+                .find(|(k, _)| split_chunk(k) == Some((base, index)))
+            {
+                Some((_, chunk)) => joined.push_str(chunk),
+                None => break,
+            }
+        }
+        cookies.push((base, Cow::Owned(joined)));
+    }
+    cookies
+}
+
+// Attempt URL decoding, skipping invalid values.
+fn decode(value: &str) -> Option<String> {
+    urlencoding::decode(value).ok().map(Cow::into_owned)
+}
+
+fn parse_pairs(input: &str) -> Vec<(&str, String)> {
+    reassemble(&raw_pairs(input))
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, decode(&value)?)))
+        .collect()
+}
+
+pub(crate) fn pairs_to_list(pairs: Vec<(&str, String)>) -> List {
+    List::from_pairs(
+        pairs
+            .into_iter()
+            .map(|(key, value)| (key, Robj::from(value))),
+    )
+}
+
+/// @title Parse cookies
+/// @description Parses cookies from a string
+///
+/// @param x A string containing the cookies
+///
+/// @return A list containing the cookies
+/// @keywords internal
+#[extendr]
+fn parse_cookies(cookie_string: Option<&str>) -> List {
+    // Use unwrap_or to default to an empty string and trim whitespace.
+    let input = cookie_string.unwrap_or("").trim();
+    if input.is_empty() {
+        return List::new(0);
+    }
+
+    pairs_to_list(parse_pairs(input))
+}
+
+/// @title Read the cookies of a request
+/// @description Parses the `Cookie` header of a request as the runtime
+///   built with `options` does: only the cookies with the configured
+///   prefix are kept, and with `cookie_keys` the cookies of tapLock
+///   that do not authenticate are dropped and reported.
+///
+/// @param cookie_string The `Cookie` header of the request
+/// @param app_url The URL of the app
+/// @param options A list of runtime options (see `runtime_options()`)
+///
+/// @return A list containing the cookies, without prefix. The names
+///   of the sealed cookies that do not authenticate are in its
+///   `tampered` attribute.
+/// @keywords internal
+#[extendr]
+fn read_cookies(cookie_string: Option<&str>, app_url: &str, options: List) -> Result<Robj> {
+    let options = RuntimeOptions::try_from(options)?;
+    let cookies = options.cookies.resolve(app_url)?;
+    cookies.parse(cookie_string.unwrap_or("")).into_list()
+}
+
+/// @title Build a cookie
+/// @description Builds the values of the `Set-Cookie` headers storing
+///   a cookie. The `Secure` attribute is set when `app_url` uses HTTPS
+///   unless configured otherwise in `options`. Values too large for a
+///   single cookie are split in chunks named `name.0`, `name.1`, ...
+///
+/// @param name The name of the cookie, without prefix
+/// @param value The value of the cookie
+/// @param app_url The URL of the app
+/// @param options A list of runtime options (see `runtime_options()`)
+/// @param max_age The lifetime of the cookie in seconds, `NULL` for a
+///   session cookie
+/// @param cookie_string The `Cookie` header of the request, used to
+///   clear chunks left over from a previous value
+///
+/// @return A character vector of cookies, with more than one element
+///   when the value had to be split in chunks
+/// @keywords internal
+#[extendr]
+fn build_cookie(
+    name: &str,
+    value: &str,
+    app_url: &str,
+    options: List,
+    max_age: Option<f64>,
+    #[default = "NULL"] cookie_string: Option<&str>,
+) -> Result<Vec<String>> {
+    let options = RuntimeOptions::try_from(options)?;
+    let cookies = options.cookies.resolve(app_url)?;
+    let existing = cookies.names(cookie_string.unwrap_or(""));
+    Ok(cookies.build(name, value, max_age.map(max_age_seconds), &existing)?)
+}
+
+/// @title Clear a cookie
+/// @description Builds the `Set-Cookie` header values that remove a
+///   cookie, and its chunks, built with the same options.
+///
+/// @param name The name of the cookie, without prefix
+/// @param app_url The URL of the app
+/// @param options A list of runtime options (see `runtime_options()`)
+/// @param cookie_string The `Cookie` header of the request, used to
+///   also clear the chunks of the cookie
+///
+/// @return A character vector of cookies
+/// @keywords internal
+#[extendr]
+fn clear_cookie(
+    name: &str,
+    app_url: &str,
+    options: List,
+    #[default = "NULL"] cookie_string: Option<&str>,
+) -> Result<Vec<String>> {
+    let options = RuntimeOptions::try_from(options)?;
+    let cookies = options.cookies.resolve(app_url)?;
+    let existing = cookies.names(cookie_string.unwrap_or(""));
+    Ok(cookies.clear(name, &existing)?)
+}
 
-    List::from_pairs(values)
+pub(crate) fn max_age_seconds(max_age: f64) -> u64 {
+    if max_age.is_finite() && max_age > 0.0 {
+        max_age.floor() as u64
+    } else {
+        0
+    }
 }
 
 extendr_module! {
     mod cookies;
     fn parse_cookies;
+    fn read_cookies;
+    fn build_cookie;
+    fn clear_cookie;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    fn sealed_builder() -> CookieBuilder {
+        let key = STANDARD.encode([7u8; 32]);
+        CookieOptions {
+            keys: Some(KeyRing::parse(&[key]).unwrap()),
+            ..CookieOptions::default()
+        }
+        .resolve("http://localhost")
+        .unwrap()
+    }
+
+    // `name=value; Path=/; ...` -> `name=value`
+    fn pair(set_cookie: &str) -> String {
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn opens_sealed_cookies() {
+        let builder = sealed_builder();
+        let cookie = pair(
+            &builder
+                .build("access_token", "Bearer abc", None, &[])
+                .unwrap()[0],
+        );
+        let parsed = builder.parse(&cookie);
+        assert_eq!(parsed.get("access_token"), Some("Bearer abc"));
+        assert!(parsed.tampered.is_empty());
+    }
+
+    #[test]
+    fn reports_tampered_cookies() {
+        let builder = sealed_builder();
+        let mut cookie = pair(
+            &builder
+                .build("access_token", "Bearer abc", None, &[])
+                .unwrap()[0],
+        );
+        // Flip one byte of the sealed value
+        let at = "access_token=".len() + 10;
+        let flipped = if &cookie[at..=at] == "A" { "B" } else { "A" };
+        cookie.replace_range(at..=at, flipped);
+
+        let header = format!("{cookie}; theme=dark");
+        let parsed = builder.parse(&header);
+        assert_eq!(parsed.get("access_token"), None);
+        assert_eq!(parsed.get("theme"), Some("dark"));
+        assert_eq!(parsed.tampered, vec!["access_token"]);
+    }
 }
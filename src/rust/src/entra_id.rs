@@ -1,21 +1,69 @@
-use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use jsonwebtoken::{decode, decode_header, DecodingKey};
 use oauth2::TokenResponse;
 use oauth2::{
     basic::{
         BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
         BasicTokenType,
     },
-    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, RedirectUrl, Scope,
+    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, RedirectUrl, Scope,
     StandardRevocableToken, StandardTokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 use crate::error::TapLockError;
+use crate::graph::{self, GraphClient};
 use crate::jwks::JwksClient;
+use crate::options::{AuthorizationUrlOptions, RuntimeOptions};
+use crate::retry::RetryPolicy;
+use crate::validation::TokenValidation;
 use crate::{OAuth2Client, OAuth2Response};
 
 const JWKS_URL: &str = "https://login.microsoftonline.com/common/discovery/keys";
 
+// Names of the built-in directory roles, by the template ids found in
+// the `wids` claim
+fn directory_role_name(id: &str) -> Option<&'static str> {
+    Some(match id.to_ascii_lowercase().as_str() {
+        "62e90394-69f5-4237-9190-012177145e10" => "Global Administrator",
+        "f2ef992c-3afb-46b9-b7cf-a126ee74c451" => "Global Reader",
+        "e8611ab8-c189-46e8-94e1-60213ab1f814" => "Privileged Role Administrator",
+        "7be44c8a-adaf-4e2a-84d6-ab2649e08a13" => "Privileged Authentication Administrator",
+        "194ae4cb-b126-40b2-bd5b-6091b380977d" => "Security Administrator",
+        "5f2222b1-57c3-48ba-8ad5-d4759f1fde6f" => "Security Operator",
+        "5d6b6bb7-de71-4623-b4af-96380a352509" => "Security Reader",
+        "b1be1c3e-b65d-4f19-8427-f6fa0d97feb9" => "Conditional Access Administrator",
+        "c4e39bd9-1100-46d3-8c65-fb160da0071f" => "Authentication Administrator",
+        "fe930be7-5e62-47db-91af-98c3a49a38b1" => "User Administrator",
+        "fdd7a751-b60b-444a-984c-02652fe8fa1c" => "Groups Administrator",
+        "729827e3-9c14-49f7-bb1b-9608f156bbb8" => "Helpdesk Administrator",
+        "966707d0-3269-4727-9be2-8c3a10f19b9d" => "Password Administrator",
+        "9b895d92-2cd3-44c7-9d02-a6ac2d5ea5c3" => "Application Administrator",
+        "158c047a-c907-4556-b7ef-446551a6b5f7" => "Cloud Application Administrator",
+        "cf1c38e5-3621-4004-a7cb-879624dced7c" => "Application Developer",
+        "8ac3fc64-6eca-42ea-9e69-59f4c7b60eb2" => "Hybrid Identity Administrator",
+        "b0f54661-2d74-4c50-afa3-1ec803f12efe" => "Billing Administrator",
+        "4d6ac14f-3453-41d0-bef9-a3e0c569773a" => "License Administrator",
+        "29232cdf-9323-42fd-ade2-1d097af3e4de" => "Exchange Administrator",
+        "f28a1f50-f6e7-4571-818b-6a12f2af6b6c" => "SharePoint Administrator",
+        "69091246-20e8-4a56-aa4d-066075b2a7a8" => "Teams Administrator",
+        "3a2c62db-5318-420d-8d74-23affee5d9d5" => "Intune Administrator",
+        "17315797-102d-40b4-93e0-432062caca18" => "Compliance Administrator",
+        "11648597-926c-4cf3-9c36-bcebb0ba8dcc" => "Power Platform Administrator",
+        "44367163-eba1-44c3-98af-f5787879f96a" => "Dynamics 365 Administrator",
+        "e3973bdf-4987-49ae-837a-ba8e231c7286" => "Azure DevOps Administrator",
+        "f023fd81-a637-4b56-95fd-791ac0226033" => "Service Support Administrator",
+        "4a5d8f65-41da-4de4-8968-e035b65339cf" => "Reports Reader",
+        "790c1fb9-7f7d-4f88-86a1-ef1f95c05c1b" => "Message Center Reader",
+        "88d8e3e3-8f55-4a1e-953a-9b9898b8876b" => "Directory Readers",
+        "9360feb5-f418-4baa-8175-e2a00bac4301" => "Directory Writers",
+        "b79fbf4d-3ef9-4689-8143-76b194e85509" => "User",
+        "10dae51f-b6af-4016-8d66-8c2a99b929b3" => "Guest User",
+        "2af84b1e-32c8-42b7-82bc-daa82404023b" => "Restricted Guest User",
+        _ => return None,
+    })
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct AzureADTokenResponseExtra {
     id_token: String,
@@ -42,7 +90,12 @@ pub struct AzureADOAuth2Client {
     client: AzureADClientFull,
     client_id: String,
     jwks_client: JwksClient,
+    graph: GraphClient,
     use_refresh_token: bool,
+    retry: RetryPolicy,
+    validation: TokenValidation,
+    scopes: Vec<Scope>,
+    auth_params: Vec<(String, String)>,
     _tenant_id: String,
 }
 
@@ -52,6 +105,97 @@ impl AzureADOAuth2Client {
     }
 }
 
+// The app roles of the user are in the `roles` claim, which is left
+// out when they have none. It is always set so that role checks work
+// the same for every provider. Directory roles (`wids`) are added to a
+// `directory_roles` claim by name, unknown (custom) roles by id.
+fn normalize_roles(claims: &mut serde_json::Value) {
+    let Some(claims) = claims.as_object_mut() else {
+        return;
+    };
+    let directory_roles: Vec<String> = claims
+        .get("wids")
+        .and_then(|wids| wids.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|wid| wid.as_str())
+        .map(|wid| directory_role_name(wid).unwrap_or(wid).to_string())
+        .collect();
+    claims
+        .entry("roles")
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+    claims.insert("directory_roles".to_string(), directory_roles.into());
+}
+
+// Groups fetched from Graph are remembered per user of a tenant
+fn user_key(claims: &serde_json::Value) -> Option<String> {
+    let claim = |name| claims.get(name).and_then(|value| value.as_str());
+    match (claim("tid"), claim("oid")) {
+        (Some(tid), Some(oid)) => Some(format!("{tid}/{oid}")),
+        _ => claim("sub").map(str::to_string),
+    }
+}
+
+// Puts back the groups of users in too many groups to be listed in the
+// token, as last fetched from Graph. When they are not known (the app
+// restarted, or they were fetched too long ago) the token is rejected
+// rather than decoded without groups, so that it is refreshed, which
+// fetches them again. While Graph is known to fail, the token is
+// decoded without groups and with a warning, which keeps it out of the
+// token cache
+fn restore_groups(graph: &GraphClient, response: &mut OAuth2Response) -> Result<(), TapLockError> {
+    let claims = &mut response.fields;
+    if claims.get("groups").is_some() || !graph::has_group_overage(claims) {
+        return Ok(());
+    }
+    let groups = user_key(claims)
+        .and_then(|user| graph.cached_groups(&user))
+        .ok_or(TapLockError::GroupsUnavailable)?;
+    match groups {
+        Ok(groups) => {
+            if let Some(claims) = claims.as_object_mut() {
+                claims.insert("groups".to_string(), groups.into());
+            }
+        }
+        Err(err) => response.warnings.push(groups_warning(&err)),
+    }
+    Ok(())
+}
+
+fn groups_warning(err: &TapLockError) -> String {
+    format!(
+        "The groups of the user could not be fetched from Microsoft Graph, \
+         the `groups` claim is left out: {err}"
+    )
+}
+
+// Fetches the groups of users in too many groups to be listed in the
+// token, using the access token issued with it. If that fails, usually
+// because the app lacks the Graph permission, the login goes on without
+// `groups` and with a warning
+async fn resolve_groups(
+    client: &AzureADOAuth2Client,
+    response: &mut OAuth2Response,
+    access_token: &str,
+) {
+    let claims = &mut response.fields;
+    if claims.get("groups").is_some() || !graph::has_group_overage(claims) {
+        return;
+    }
+    let result = match user_key(claims) {
+        Some(user) => client.graph.fetch_groups(&user, access_token).await,
+        None => Err(TapLockError::new("the token has no `oid` or `sub`")),
+    };
+    match result {
+        Ok(groups) => {
+            if let Some(claims) = claims.as_object_mut() {
+                claims.insert("groups".to_string(), groups.into());
+            }
+        }
+        Err(err) => response.warnings.push(groups_warning(&err)),
+    }
+}
+
 fn decode_access_token(
     client: &AzureADOAuth2Client,
     access_token: String,
@@ -61,21 +205,26 @@ fn decode_access_token(
     let kid = jwt_header.kid.ok_or(TapLockError::KidNotFound)?;
     let algo = jwt_header.alg;
     let decoding_key = client.get_jwk(&kid).ok_or(TapLockError::KidNotFound)?;
-    let mut validation = Validation::new(algo);
-
-    validation.set_audience(&[&client.client_id]);
+    let validation = client.validation.jwt_validation(algo, &client.client_id);
 
     let val = decode::<serde_json::Value>(
         token_trim,
         &DecodingKey::from_jwk(&decoding_key)?,
         &validation,
     )?;
-
-    Ok(OAuth2Response {
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    let mut fields = val.claims;
+    normalize_roles(&mut fields);
+    let mut response = OAuth2Response {
         access_token,
         refresh_token: None,
-        fields: val.claims,
-    })
+        fields,
+        warnings: Vec::new(),
+    };
+    restore_groups(&client.graph, &mut response)?;
+    Ok(response)
 }
 
 async fn decode_token_and_maybe_refresh_jwks(
@@ -88,20 +237,24 @@ async fn decode_token_and_maybe_refresh_jwks(
 
     let decoding_key = client.jwks_client.get_key_with_refresh(&kid).await?;
     let algo = jwt_header.alg;
-    let mut validation = Validation::new(algo);
-
-    validation.set_audience(&[&client.client_id]);
+    let validation = client.validation.jwt_validation(algo, &client.client_id);
 
     let val = decode::<serde_json::Value>(
         token_trim,
         &DecodingKey::from_jwk(&decoding_key)?,
         &validation,
     )?;
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    let mut fields = val.claims;
+    normalize_roles(&mut fields);
 
     Ok(OAuth2Response {
         access_token,
         refresh_token: None,
-        fields: val.claims,
+        fields,
+        warnings: Vec::new(),
     })
 }
 
@@ -111,28 +264,41 @@ pub async fn build_oauth2_state_azure_ad(
     app_url: &str,
     use_refresh_token: bool,
     tenant_id: &str, // Add tenant ID as a parameter
+    options: &RuntimeOptions,
 ) -> std::result::Result<AzureADOAuth2Client, TapLockError> {
     let auth_url = format!("https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/authorize");
     let token_url = format!("https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/token");
-    let app_url = app_url.trim_end_matches('/');
-    let redirect_url = format!("{app_url}/login");
 
     let client = Client::new(ClientId::new(client_id.to_string()))
         .set_client_secret(ClientSecret::new(client_secret.to_string()))
         .set_auth_uri(AuthUrl::new(auth_url)?)
-        .set_token_uri(TokenUrl::new(token_url)?)
-        .set_redirect_uri(RedirectUrl::new(redirect_url)?);
+        .set_token_uri(TokenUrl::new(token_url.clone())?)
+        .set_redirect_uri(options.redirect_url(app_url)?);
 
     let reqwest_client = reqwest::Client::new();
 
-    let jwks_client = JwksClient::new(JWKS_URL.to_string(), reqwest_client.clone()).await?;
+    let jwks_client =
+        JwksClient::new(JWKS_URL.to_string(), reqwest_client.clone(), options.retry).await?;
+    let graph = GraphClient::new(&options.graph_url, reqwest_client.clone(), options.retry)
+        .on_behalf_of(&token_url, client_id, client_secret);
 
     Ok(AzureADOAuth2Client {
         reqwest_client,
         client,
         jwks_client,
+        graph,
         client_id: client_id.to_string(),
         use_refresh_token,
+        retry: options.retry,
+        validation: options.validation.clone(),
+        scopes: options.scopes_or(&[
+            &format!("{client_id}/.default"),
+            "openid",
+            "email",
+            "profile",
+            "offline_access",
+        ]),
+        auth_params: options.auth_params_or(&[]),
         _tenant_id: tenant_id.to_string(), // Store tenant ID
     })
 }
@@ -148,17 +314,20 @@ impl OAuth2Client for AzureADOAuth2Client {
         }
 
         let token_result = self
-            .client
-            .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
-            .add_scope(Scope::new(format!("{}/.default", self.client_id)))
-            .add_scopes(
-                ["openid", "email", "profile", "offline_access"].map(|s| Scope::new(s.into())),
-            ) // Add offline_access
-            .request_async(&self.reqwest_client)
+            .retry
+            .token_request(|| async {
+                Ok(self
+                    .client
+                    .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+                    .add_scopes(self.scopes.iter().cloned())
+                    .request_async(&self.reqwest_client)
+                    .await?)
+            })
             .await?;
 
         let access_token = token_result.extra_fields().id_token.clone();
         let mut response = decode_token_and_maybe_refresh_jwks(self, access_token).await?;
+        resolve_groups(self, &mut response, token_result.access_token().secret()).await;
         if self.use_refresh_token {
             response.refresh_token = Some(
                 token_result
@@ -172,15 +341,24 @@ impl OAuth2Client for AzureADOAuth2Client {
     async fn exchange_code(
         &self,
         code: String,
+        redirect_url: Option<RedirectUrl>,
     ) -> std::result::Result<OAuth2Response, TapLockError> {
         let token_result = self
-            .client
-            .exchange_code(AuthorizationCode::new(code))
-            .request_async(&self.reqwest_client)
+            .retry
+            .token_request(|| async {
+                let mut request = self
+                    .client
+                    .exchange_code(AuthorizationCode::new(code.clone()));
+                if let Some(redirect_url) = &redirect_url {
+                    request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+                }
+                Ok(request.request_async(&self.reqwest_client).await?)
+            })
             .await?;
 
         let access_token = token_result.extra_fields().id_token.clone();
         let mut response = decode_token_and_maybe_refresh_jwks(self, access_token).await?;
+        resolve_groups(self, &mut response, token_result.access_token().secret()).await;
 
         if self.use_refresh_token {
             response.refresh_token = token_result.refresh_token().map(|rt| rt.secret().clone());
@@ -195,15 +373,82 @@ impl OAuth2Client for AzureADOAuth2Client {
         let response = decode_access_token(self, access_token)?;
         Ok(response)
     }
-    fn get_authorization_url(&self) -> String {
-        let (auth_url, _csrf_token) = self
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String {
+        let mut request = self
             .client
-            .authorize_url(CsrfToken::new_random)
-            .add_scope(Scope::new(format!("{}/.default", self.client_id)))
-            .add_scopes(
-                ["openid", "email", "profile", "offline_access"].map(|s| Scope::new(s.into())),
-            ) // Add offline_access
-            .url();
+            .authorize_url(|| options.csrf_token())
+            .add_scopes(self.scopes.iter().cloned());
+        if let Some(redirect_url) = &options.redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        for (name, value) in options.merge_into(&self.auth_params) {
+            request = request.add_extra_param(name, value);
+        }
+        let (auth_url, _csrf_token) = request.url();
         auth_url.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryPolicy;
+    use serde_json::json;
+
+    fn graph() -> GraphClient {
+        GraphClient::new(
+            "http://127.0.0.1:9",
+            reqwest::Client::new(),
+            RetryPolicy::default(),
+        )
+    }
+
+    fn response(fields: serde_json::Value) -> OAuth2Response {
+        OAuth2Response {
+            access_token: String::new(),
+            refresh_token: None,
+            fields,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rejects_overage_tokens_with_unknown_groups() {
+        let mut response = response(json!({ "tid": "tenant", "oid": "alice", "hasgroups": true }));
+        assert!(matches!(
+            restore_groups(&graph(), &mut response),
+            Err(TapLockError::GroupsUnavailable)
+        ));
+    }
+
+    #[test]
+    fn decodes_overage_tokens_with_a_warning_while_graph_fails() {
+        let graph = graph();
+        let claims = json!({ "tid": "tenant", "oid": "alice", "hasgroups": true });
+        // Nothing listens on port 9
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        assert!(runtime
+            .block_on(graph.fetch_groups("tenant/alice", "token"))
+            .is_err());
+
+        let mut response = response(claims);
+        restore_groups(&graph, &mut response).unwrap();
+        assert!(response.fields.get("groups").is_none());
+        assert_eq!(response.warnings.len(), 1);
+    }
+
+    #[test]
+    fn leaves_tokens_listing_their_groups_alone() {
+        let mut listed = response(json!({ "oid": "alice", "groups": ["a"] }));
+        restore_groups(&graph(), &mut listed).unwrap();
+        assert_eq!(listed.fields["groups"], json!(["a"]));
+
+        let mut without = response(json!({ "oid": "alice" }));
+        restore_groups(&graph(), &mut without).unwrap();
+        assert!(without.fields.get("groups").is_none());
+        assert!(without.warnings.is_empty());
+    }
+}
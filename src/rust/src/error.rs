@@ -1,5 +1,6 @@
 use extendr_api::IntoRobj;
-use oauth2::{url, ErrorResponse};
+use oauth2::basic::BasicErrorResponse;
+use oauth2::{url, HttpClientError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -14,27 +15,84 @@ pub enum TapLockError {
     HttpRequest(#[from] reqwest::Error),
     #[error("Request Token error: {0}")]
     RequestToken(String),
+    #[error("OAuth2 server error: {0}")]
+    OAuth2Server(String),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("JSON Web Token error: {0}")]
     JwtError(#[from] jsonwebtoken::errors::Error),
     #[error("KID not found in JWKs")]
     KidNotFound,
+    #[error("Account domain `{0}` is not allowed")]
+    HostedDomainNotAllowed(String),
+    #[error("GitHub user `{0}` is not a member of an allowed organization or team")]
+    OrganizationNotAllowed(String),
+    #[error("Cookie `{0}` failed authentication")]
+    CookieTampered(String),
+    /// A token rejected recently, with the message of the original error
+    #[error("{0}")]
+    TokenRejected(String),
+    #[error("Token was issued to an unauthorized party `{0}`")]
+    UnauthorizedParty(String),
+    #[error("Token is older than the maximum age, please log in again")]
+    TokenTooOld,
+    #[error("Session expired, please log in again")]
+    SessionExpired,
+    /// The groups of a user in too many groups to be listed in the
+    /// token are not known, the token has to be refreshed to get them
+    #[error("The groups of the user are not known, the token must be refreshed")]
+    GroupsUnavailable,
+    /// The claims of an opaque token are not remembered, they are only
+    /// fetched when the token is issued
+    #[error("The token is not known, it must be refreshed")]
+    UnknownToken,
+    #[error("Deadline exceeded after {0} attempt(s)")]
+    DeadlineExceeded(u32),
 }
 
 impl TapLockError {
     pub fn new(msg: impl Into<String>) -> Self {
         TapLockError::Msg(msg.into())
     }
+
+    /// Whether the error is likely to go away if the same request is
+    /// sent again: connection failures, timeouts and 5xx / 429
+    /// responses. Definitive OAuth2 error responses (e.g. `invalid_grant`)
+    /// are never transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TapLockError::HttpRequest(err) => {
+                err.is_connect()
+                    || err.is_timeout()
+                    || err.status().is_some_and(|status| {
+                        status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the request failed before reaching the server, which makes
+    /// it safe to retry even when the request itself is not idempotent.
+    pub fn is_connect(&self) -> bool {
+        matches!(self, TapLockError::HttpRequest(err) if err.is_connect())
+    }
 }
 
-impl<TE, TR> From<oauth2::RequestTokenError<TE, TR>> for TapLockError
-where
-    TE: std::error::Error + 'static,
-    TR: ErrorResponse + 'static,
-{
-    fn from(value: oauth2::RequestTokenError<TE, TR>) -> Self {
-        TapLockError::RequestToken(value.to_string())
+type RequestTokenError =
+    oauth2::RequestTokenError<HttpClientError<reqwest::Error>, BasicErrorResponse>;
+
+impl From<RequestTokenError> for TapLockError {
+    fn from(value: RequestTokenError) -> Self {
+        match value {
+            oauth2::RequestTokenError::ServerResponse(err) => {
+                TapLockError::OAuth2Server(err.to_string())
+            }
+            oauth2::RequestTokenError::Request(HttpClientError::Reqwest(err)) => {
+                TapLockError::HttpRequest(*err)
+            }
+            other => TapLockError::RequestToken(other.to_string()),
+        }
     }
 }
 
@@ -44,7 +102,7 @@ impl From<TapLockError> for extendr_api::Error {
     }
 }
 
-impl IntoRobj for TapLockError {
+impl IntoRobj for &TapLockError {
     fn into_robj(self) -> extendr_api::Robj {
         extendr_api::Strings::from(self.to_string()).into_robj()
     }
@@ -0,0 +1,248 @@
+use oauth2::TokenResponse;
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenResponse,
+    },
+    AuthType, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, RedirectUrl, Scope,
+    StandardRevocableToken, TokenUrl,
+};
+use std::borrow::Cow;
+
+use crate::error::TapLockError;
+use crate::options::{AuthorizationUrlOptions, GenericProviderOptions, RuntimeOptions};
+use crate::retry::RetryPolicy;
+use crate::userinfo::UserInfoCache;
+use crate::{OAuth2Client, OAuth2Response};
+
+type GenericClientFull = Client<
+    BasicErrorResponse,
+    BasicTokenResponse,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    oauth2::EndpointSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointSet,
+>;
+
+/// A provider that speaks OAuth2 without OpenID Connect: its access
+/// tokens are opaque and the user is described by a userinfo endpoint.
+#[derive(Clone)]
+pub struct GenericOAuth2Client {
+    reqwest_client: reqwest::Client,
+    client: GenericClientFull,
+    userinfo_url: String,
+    claims: Vec<(String, String)>,
+    userinfo: UserInfoCache,
+    use_refresh_token: bool,
+    retry: RetryPolicy,
+    scopes: Vec<Scope>,
+    auth_params: Vec<(String, String)>,
+}
+
+fn lookup<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(value, |value, segment| value.get(segment))
+        .filter(|value| !value.is_null())
+}
+
+impl GenericOAuth2Client {
+    // The claims are the userinfo response, with the mapped claims
+    // copied to their names
+    async fn fetch_claims(
+        &self,
+        access_token: &str,
+        expires_at: Option<u64>,
+    ) -> Result<serde_json::Value, TapLockError> {
+        let userinfo = self
+            .retry
+            .run(TapLockError::is_transient, || async {
+                Ok(self
+                    .reqwest_client
+                    .get(&self.userinfo_url)
+                    .bearer_auth(access_token)
+                    .header(reqwest::header::ACCEPT, "application/json")
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<serde_json::Value>()
+                    .await?)
+            })
+            .await?;
+
+        let mut claims = match &userinfo {
+            serde_json::Value::Object(fields) => fields.clone(),
+            _ => serde_json::Map::new(),
+        };
+        for (claim, path) in &self.claims {
+            match lookup(&userinfo, path) {
+                // The subject is always a string, even when the
+                // provider identifies users by number
+                Some(serde_json::Value::Number(sub)) if claim == "sub" => {
+                    claims.insert(claim.clone(), sub.to_string().into());
+                }
+                Some(value) => {
+                    claims.insert(claim.clone(), value.clone());
+                }
+                None => {
+                    claims.remove(claim);
+                }
+            }
+        }
+        if !claims.get("sub").is_some_and(|sub| sub.is_string()) {
+            let path = self
+                .claims
+                .iter()
+                .find(|(claim, _)| claim == "sub")
+                .map_or("sub", |(_, path)| path.as_str());
+            return Err(TapLockError::new(format!(
+                "The userinfo response has no subject at `{path}`"
+            )));
+        }
+        if let Some(exp) = expires_at {
+            claims.insert("exp".to_string(), exp.into());
+        }
+        Ok(claims.into())
+    }
+
+    // Fetches the claims of a new token and remembers them
+    async fn token_response(
+        &self,
+        token_result: &BasicTokenResponse,
+    ) -> Result<OAuth2Response, TapLockError> {
+        let access_token = token_result.access_token().secret().clone();
+        let expires_at = token_result
+            .expires_in()
+            .map(|expires_in| self.userinfo.now().saturating_add(expires_in.as_secs()));
+        let result = self.fetch_claims(&access_token, expires_at).await;
+        self.userinfo.insert(&access_token, &result, expires_at);
+        Ok(OAuth2Response {
+            access_token,
+            refresh_token: None,
+            fields: result?,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+pub async fn build_oauth2_state_generic(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    provider: GenericProviderOptions,
+    options: &RuntimeOptions,
+) -> std::result::Result<GenericOAuth2Client, TapLockError> {
+    let auth_type = if provider.secret_in_body {
+        AuthType::RequestBody
+    } else {
+        AuthType::BasicAuth
+    };
+    let client = Client::new(ClientId::new(client_id.to_string()))
+        .set_client_secret(ClientSecret::new(client_secret.to_string()))
+        .set_auth_type(auth_type)
+        .set_auth_uri(AuthUrl::new(provider.auth_url)?)
+        .set_token_uri(TokenUrl::new(provider.token_url)?)
+        .set_redirect_uri(options.redirect_url(app_url)?);
+
+    Ok(GenericOAuth2Client {
+        reqwest_client: reqwest::Client::new(),
+        client,
+        userinfo_url: provider.userinfo_url,
+        claims: provider.claims,
+        userinfo: UserInfoCache::new(options),
+        use_refresh_token: provider.use_refresh_token,
+        retry: options.retry,
+        scopes: options.oauth2_scopes_or(&[]),
+        auth_params: options.auth_params_or(&[]),
+    })
+}
+
+#[async_trait::async_trait]
+impl OAuth2Client for GenericOAuth2Client {
+    async fn exchange_refresh_token(
+        &self,
+        refresh_token: String,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        if !self.use_refresh_token {
+            return Err(TapLockError::new("Refresh token is disabled"));
+        }
+        let token_result = self
+            .retry
+            .token_request(|| async {
+                Ok(self
+                    .client
+                    .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+                    .add_scopes(self.scopes.iter().cloned())
+                    .request_async(&self.reqwest_client)
+                    .await?)
+            })
+            .await?;
+
+        let mut response = self.token_response(&token_result).await?;
+        response.refresh_token = Some(
+            token_result
+                .refresh_token()
+                .map(|rt| rt.secret().clone())
+                .unwrap_or(refresh_token),
+        );
+        Ok(response)
+    }
+    async fn exchange_code(
+        &self,
+        code: String,
+        redirect_url: Option<RedirectUrl>,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let token_result = self
+            .retry
+            .token_request(|| async {
+                let mut request = self
+                    .client
+                    .exchange_code(AuthorizationCode::new(code.clone()));
+                if let Some(redirect_url) = &redirect_url {
+                    request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+                }
+                Ok(request.request_async(&self.reqwest_client).await?)
+            })
+            .await?;
+
+        let mut response = self.token_response(&token_result).await?;
+        if self.use_refresh_token {
+            response.refresh_token = token_result.refresh_token().map(|rt| rt.secret().clone());
+        }
+        Ok(response)
+    }
+    // Tokens cannot be verified locally, their claims are the ones
+    // fetched from the userinfo endpoint when they were issued (see
+    // `UserInfoCache`)
+    fn decode_access_token(
+        &self,
+        access_token: String,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let token = access_token.trim_start_matches("Bearer").trim();
+        let fields = self.userinfo.get(token)?;
+
+        Ok(OAuth2Response {
+            access_token,
+            refresh_token: None,
+            fields,
+            warnings: Vec::new(),
+        })
+    }
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String {
+        let mut request = self
+            .client
+            .authorize_url(|| options.csrf_token())
+            .add_scopes(self.scopes.iter().cloned());
+        if let Some(redirect_url) = &options.redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        for (name, value) in options.merge_into(&self.auth_params) {
+            request = request.add_extra_param(name, value);
+        }
+        let (auth_url, _csrf_token) = request.url();
+        auth_url.to_string()
+    }
+}
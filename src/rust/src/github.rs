@@ -0,0 +1,322 @@
+use oauth2::TokenResponse;
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenResponse,
+    },
+    AuthType, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, RedirectUrl, Scope,
+    StandardRevocableToken, TokenUrl,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+use crate::error::TapLockError;
+use crate::options::{AuthorizationUrlOptions, RuntimeOptions};
+use crate::retry::RetryPolicy;
+use crate::userinfo::UserInfoCache;
+use crate::{OAuth2Client, OAuth2Response};
+
+// The API rejects requests without a user agent
+const USER_AGENT: &str = "tapLock";
+
+// Lists are fetched 100 items at a time, up to this many pages
+const MAX_PAGES: usize = 10;
+
+type GitHubClientFull = Client<
+    BasicErrorResponse,
+    BasicTokenResponse,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    oauth2::EndpointSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointSet,
+>;
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+#[derive(Deserialize)]
+struct GitHubOrg {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubTeam {
+    slug: String,
+    organization: GitHubOrg,
+}
+
+#[derive(Clone)]
+pub struct GitHubOAuth2Client {
+    reqwest_client: reqwest::Client,
+    client: GitHubClientFull,
+    api_url: String,
+    claims: UserInfoCache,
+    retry: RetryPolicy,
+    scopes: Vec<Scope>,
+    auth_params: Vec<(String, String)>,
+    organizations: Vec<String>,
+}
+
+impl GitHubOAuth2Client {
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        access_token: &str,
+    ) -> Result<T, TapLockError> {
+        let url = format!("{}{path}", self.api_url);
+        self.retry
+            .run(TapLockError::is_transient, || async {
+                Ok(self
+                    .reqwest_client
+                    .get(&url)
+                    .bearer_auth(access_token)
+                    .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+                    .header(reqwest::header::USER_AGENT, USER_AGENT)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<T>()
+                    .await?)
+            })
+            .await
+    }
+
+    async fn get_pages<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        access_token: &str,
+    ) -> Result<Vec<T>, TapLockError> {
+        let mut items = Vec::new();
+        for page in 1..=MAX_PAGES {
+            let batch: Vec<T> = self
+                .get(&format!("{path}?per_page=100&page={page}"), access_token)
+                .await?;
+            let done = batch.len() < 100;
+            items.extend(batch);
+            if done {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    // Builds OpenID Connect like claims from the API: the user, their
+    // primary email and the organizations and teams they belong to
+    // (as `org/team`)
+    async fn fetch_claims(
+        &self,
+        access_token: &str,
+        expires_at: Option<u64>,
+    ) -> Result<serde_json::Value, TapLockError> {
+        let user: serde_json::Value = self.get("/user", access_token).await?;
+        // Requires the `user:email` scope, the public email is used
+        // without it
+        let emails: Vec<GitHubEmail> = self
+            .get("/user/emails", access_token)
+            .await
+            .unwrap_or_default();
+        let orgs: Vec<GitHubOrg> = self.get_pages("/user/orgs", access_token).await?;
+        let teams: Vec<GitHubTeam> = self.get_pages("/user/teams", access_token).await?;
+
+        let primary = emails.into_iter().find(|email| email.primary);
+        let (email, email_verified) = match primary {
+            Some(primary) => (Some(primary.email), primary.verified),
+            None => (
+                user.get("email")
+                    .and_then(|email| email.as_str())
+                    .map(str::to_string),
+                false,
+            ),
+        };
+        let sub = match user.get("id") {
+            Some(serde_json::Value::Number(id)) => id.to_string(),
+            _ => return Err(TapLockError::new("GitHub user has no `id`")),
+        };
+        let mut claims = serde_json::json!({
+            "iss": self.api_url,
+            "sub": sub,
+            "login": user.get("login"),
+            "preferred_username": user.get("login"),
+            "name": user.get("name"),
+            "email": email,
+            "email_verified": email_verified,
+            "picture": user.get("avatar_url"),
+            "profile": user.get("html_url"),
+            "orgs": orgs.into_iter().map(|org| org.login).collect::<Vec<_>>(),
+            "teams": teams
+                .into_iter()
+                .map(|team| format!("{}/{}", team.organization.login, team.slug))
+                .collect::<Vec<_>>(),
+        });
+        if let (Some(exp), Some(claims)) = (expires_at, claims.as_object_mut()) {
+            claims.insert("exp".to_string(), exp.into());
+        }
+        self.check_organizations(&claims)?;
+        Ok(claims)
+    }
+
+    // Users must belong to one of the allowed organizations (`org`) or
+    // teams (`org/team`), if any
+    fn check_organizations(&self, claims: &serde_json::Value) -> Result<(), TapLockError> {
+        if self.organizations.is_empty() {
+            return Ok(());
+        }
+        let member_of = |claim: &str, name: &str| {
+            claims
+                .get(claim)
+                .and_then(|values| values.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|value| value.as_str())
+                .any(|value| value.eq_ignore_ascii_case(name))
+        };
+        let allowed = self.organizations.iter().any(|name| {
+            if name.contains('/') {
+                member_of("teams", name)
+            } else {
+                member_of("orgs", name)
+            }
+        });
+        if allowed {
+            return Ok(());
+        }
+        let login = claims.get("login").and_then(|login| login.as_str());
+        Err(TapLockError::OrganizationNotAllowed(
+            login.unwrap_or_default().to_string(),
+        ))
+    }
+
+    // Fetches the claims of a new token and remembers them
+    async fn token_response(
+        &self,
+        token_result: &BasicTokenResponse,
+    ) -> Result<OAuth2Response, TapLockError> {
+        let access_token = token_result.access_token().secret().clone();
+        let expires_at = token_result
+            .expires_in()
+            .map(|expires_in| self.claims.now().saturating_add(expires_in.as_secs()));
+        let result = self.fetch_claims(&access_token, expires_at).await;
+        self.claims.insert(&access_token, &result, expires_at);
+        Ok(OAuth2Response {
+            access_token,
+            refresh_token: token_result.refresh_token().map(|rt| rt.secret().clone()),
+            fields: result?,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+pub async fn build_oauth2_state_github(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    base_url: &str,
+    api_url: &str,
+    organizations: Vec<String>,
+    options: &RuntimeOptions,
+) -> std::result::Result<GitHubOAuth2Client, TapLockError> {
+    let base_url = base_url.trim_end_matches('/');
+    let auth_url = format!("{base_url}/login/oauth/authorize");
+    let token_url = format!("{base_url}/login/oauth/access_token");
+
+    let client = Client::new(ClientId::new(client_id.to_string()))
+        .set_client_secret(ClientSecret::new(client_secret.to_string()))
+        .set_auth_type(AuthType::RequestBody)
+        .set_auth_uri(AuthUrl::new(auth_url)?)
+        .set_token_uri(TokenUrl::new(token_url)?)
+        .set_redirect_uri(options.redirect_url(app_url)?);
+
+    Ok(GitHubOAuth2Client {
+        reqwest_client: reqwest::Client::new(),
+        client,
+        api_url: api_url.trim_end_matches('/').to_string(),
+        claims: UserInfoCache::new(options),
+        retry: options.retry,
+        scopes: options.oauth2_scopes_or(&["read:user", "user:email", "read:org"]),
+        auth_params: options.auth_params_or(&[]),
+        organizations,
+    })
+}
+
+#[async_trait::async_trait]
+impl OAuth2Client for GitHubOAuth2Client {
+    // Only GitHub Apps with expiring user tokens issue refresh tokens
+    async fn exchange_refresh_token(
+        &self,
+        refresh_token: String,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let token_result = self
+            .retry
+            .token_request(|| async {
+                Ok(self
+                    .client
+                    .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+                    .request_async(&self.reqwest_client)
+                    .await?)
+            })
+            .await?;
+
+        let mut response = self.token_response(&token_result).await?;
+        response.refresh_token.get_or_insert(refresh_token);
+        Ok(response)
+    }
+    async fn exchange_code(
+        &self,
+        code: String,
+        redirect_url: Option<RedirectUrl>,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let token_result = self
+            .retry
+            .token_request(|| async {
+                let mut request = self
+                    .client
+                    .exchange_code(AuthorizationCode::new(code.clone()));
+                if let Some(redirect_url) = &redirect_url {
+                    request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+                }
+                Ok(request.request_async(&self.reqwest_client).await?)
+            })
+            .await?;
+
+        self.token_response(&token_result).await
+    }
+    // Tokens cannot be verified locally, their claims are the ones
+    // fetched from the API when they were issued (see `UserInfoCache`)
+    fn decode_access_token(
+        &self,
+        access_token: String,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let token = access_token.trim_start_matches("Bearer").trim();
+        let fields = self.claims.get(token)?;
+
+        Ok(OAuth2Response {
+            access_token,
+            refresh_token: None,
+            fields,
+            warnings: Vec::new(),
+        })
+    }
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String {
+        let mut request = self
+            .client
+            .authorize_url(|| options.csrf_token())
+            .add_scopes(self.scopes.iter().cloned());
+        if let Some(redirect_url) = &options.redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        for (name, value) in options.merge_into(&self.auth_params) {
+            request = request.add_extra_param(name, value);
+        }
+        let (auth_url, _csrf_token) = request.url();
+        auth_url.to_string()
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use crate::error::TapLockError;
+use crate::oidc::{self, OidcOAuth2Client, OidcProvider};
+use crate::options::RuntimeOptions;
+
+// GitLab puts the direct groups of a user (as full paths such as
+// `org/team`) in the `groups_direct` claim of the ID token. They are
+// copied to `groups`, which is always set
+fn normalize_claims(claims: &mut serde_json::Value) {
+    let Some(claims) = claims.as_object_mut() else {
+        return;
+    };
+    let groups = claims
+        .get("groups_direct")
+        .cloned()
+        .unwrap_or_else(|| serde_json::Value::Array(Vec::new()));
+    claims.entry("groups").or_insert(groups);
+}
+
+fn provider(base_url: &str) -> OidcProvider {
+    let base_url = base_url.trim_end_matches('/');
+    OidcProvider {
+        issuer: base_url.to_string(),
+        auth_url: format!("{base_url}/oauth/authorize"),
+        token_url: format!("{base_url}/oauth/token"),
+        jwks_url: format!("{base_url}/oauth/discovery/keys"),
+        // GitLab always issues refresh tokens and has no
+        // `offline_access` scope
+        default_scopes: &["openid", "email", "profile"],
+        auth_params: Vec::new(),
+        normalize_claims: Arc::new(normalize_claims),
+        sign_client_secret: None,
+    }
+}
+
+pub async fn build_oauth2_state_gitlab(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    base_url: &str,
+    use_refresh_token: bool,
+    options: &RuntimeOptions,
+) -> std::result::Result<OidcOAuth2Client, TapLockError> {
+    oidc::build_oauth2_state_oidc(
+        client_id,
+        client_secret,
+        app_url,
+        provider(base_url),
+        use_refresh_token,
+        options,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn derives_the_endpoints_from_the_base_url() {
+        let provider = provider("https://gitlab.example.com/");
+        assert_eq!(provider.issuer, "https://gitlab.example.com");
+        assert_eq!(
+            provider.auth_url,
+            "https://gitlab.example.com/oauth/authorize"
+        );
+        assert_eq!(provider.token_url, "https://gitlab.example.com/oauth/token");
+        assert_eq!(
+            provider.jwks_url,
+            "https://gitlab.example.com/oauth/discovery/keys"
+        );
+    }
+
+    #[test]
+    fn copies_the_direct_groups() {
+        let mut claims = json!({ "groups_direct": ["org/team"] });
+        normalize_claims(&mut claims);
+        assert_eq!(claims["groups"], json!(["org/team"]));
+
+        let mut claims = json!({});
+        normalize_claims(&mut claims);
+        assert_eq!(claims["groups"], json!([]));
+    }
+}
@@ -1,17 +1,21 @@
-use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use jsonwebtoken::{decode, decode_header, DecodingKey};
 use oauth2::TokenResponse;
 use oauth2::{
     basic::{
         BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
         BasicTokenType,
     },
-    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, RedirectUrl, Scope,
+    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, RedirectUrl, Scope,
     StandardRevocableToken, StandardTokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 use crate::error::TapLockError;
 use crate::jwks::JwksClient;
+use crate::options::{AuthorizationUrlOptions, RuntimeOptions};
+use crate::retry::RetryPolicy;
+use crate::validation::TokenValidation;
 use crate::{OAuth2Client, OAuth2Response};
 
 const AUTH_BASE_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
@@ -45,12 +49,39 @@ pub struct GoogleOAuth2Client {
     client_id: String,
     jwks_client: JwksClient,
     use_refresh_token: bool,
+    retry: RetryPolicy,
+    validation: TokenValidation,
+    scopes: Vec<Scope>,
+    auth_params: Vec<(String, String)>,
+    hosted_domains: Vec<String>,
 }
 
 impl GoogleOAuth2Client {
     fn get_jwk(&self, kid: &str) -> Option<jsonwebtoken::jwk::Jwk> {
         self.jwks_client.get_key(kid)
     }
+
+    // The `hd` parameter of the authorization URL is only a hint, so the
+    // claim has to be checked on every token we accept.
+    fn check_hosted_domain(&self, claims: &serde_json::Value) -> Result<(), TapLockError> {
+        if self.hosted_domains.is_empty() {
+            return Ok(());
+        }
+        let hd = claims.get("hd").and_then(|hd| hd.as_str());
+        match hd {
+            Some(hd)
+                if self
+                    .hosted_domains
+                    .iter()
+                    .any(|d| d.eq_ignore_ascii_case(hd)) =>
+            {
+                Ok(())
+            }
+            _ => Err(TapLockError::HostedDomainNotAllowed(
+                hd.unwrap_or_default().to_string(),
+            )),
+        }
+    }
 }
 
 fn decode_access_token(
@@ -62,18 +93,22 @@ fn decode_access_token(
     let kid = jwt_header.kid.ok_or(TapLockError::KidNotFound)?;
     let algo = jwt_header.alg;
     let decoding_key = client.get_jwk(&kid).ok_or(TapLockError::KidNotFound)?;
-    let mut validation = Validation::new(algo);
-    validation.set_audience(&[&client.client_id]);
+    let validation = client.validation.jwt_validation(algo, &client.client_id);
     let val = decode::<serde_json::Value>(
         token_trim,
         &DecodingKey::from_jwk(&decoding_key)?,
         &validation,
     )?;
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    client.check_hosted_domain(&val.claims)?;
 
     Ok(OAuth2Response {
         access_token,
         refresh_token: None,
         fields: val.claims,
+        warnings: Vec::new(),
     })
 }
 
@@ -87,18 +122,22 @@ async fn decode_token_and_maybe_refresh_jwks(
 
     let decoding_key = client.jwks_client.get_key_with_refresh(&kid).await?;
     let algo = jwt_header.alg;
-    let mut validation = Validation::new(algo);
-    validation.set_audience(&[&client.client_id]);
+    let validation = client.validation.jwt_validation(algo, &client.client_id);
     let val = decode::<serde_json::Value>(
         token_trim,
         &DecodingKey::from_jwk(&decoding_key)?,
         &validation,
     )?;
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    client.check_hosted_domain(&val.claims)?;
 
     Ok(OAuth2Response {
         access_token,
         refresh_token: None,
         fields: val.claims,
+        warnings: Vec::new(),
     })
 }
 
@@ -107,19 +146,31 @@ pub async fn build_oauth2_state_google(
     client_secret: &str,
     app_url: &str,
     use_refresh_token: bool,
+    hosted_domains: Vec<String>,
+    options: &RuntimeOptions,
 ) -> std::result::Result<GoogleOAuth2Client, TapLockError> {
-    let app_url = app_url.trim_end_matches('/');
-    let redirect_url = format!("{app_url}/login");
-
     let client = Client::new(ClientId::new(client_id.to_string()))
         .set_client_secret(ClientSecret::new(client_secret.to_string()))
         .set_auth_uri(AuthUrl::new(AUTH_BASE_URL.to_string())?)
         .set_token_uri(TokenUrl::new(TOKEN_URL.to_string())?)
-        .set_redirect_uri(RedirectUrl::new(redirect_url)?);
+        .set_redirect_uri(options.redirect_url(app_url)?);
 
     let reqwest_client = reqwest::Client::new();
 
-    let jwks_client = JwksClient::new(JWKS_URL.to_string(), reqwest_client.clone()).await?;
+    let jwks_client =
+        JwksClient::new(JWKS_URL.to_string(), reqwest_client.clone(), options.retry).await?;
+
+    let mut auth_params =
+        options.auth_params_or(&[("access_type", "offline"), ("prompt", "consent")]);
+    if !hosted_domains.is_empty() && !auth_params.iter().any(|(name, _)| name == "hd") {
+        // With several allowed domains we can only ask Google to restrict
+        // the account chooser to Workspace accounts in general.
+        let hd = match hosted_domains.as_slice() {
+            [domain] => domain.clone(),
+            _ => "*".to_string(),
+        };
+        auth_params.push(("hd".to_string(), hd));
+    }
 
     Ok(GoogleOAuth2Client {
         reqwest_client,
@@ -127,6 +178,11 @@ pub async fn build_oauth2_state_google(
         jwks_client,
         client_id: client_id.to_string(),
         use_refresh_token,
+        retry: options.retry,
+        validation: options.validation.clone(),
+        scopes: options.scopes_or(&["openid", "email", "profile"]),
+        auth_params,
+        hosted_domains,
     })
 }
 
@@ -140,10 +196,15 @@ impl OAuth2Client for GoogleOAuth2Client {
             return Err(TapLockError::new("Refresh token is disabled"));
         }
         let token_result = self
-            .client
-            .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
-            .add_scopes(["openid", "email", "profile"].map(|s| Scope::new(s.into())))
-            .request_async(&self.reqwest_client)
+            .retry
+            .token_request(|| async {
+                Ok(self
+                    .client
+                    .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+                    .add_scopes(self.scopes.iter().cloned())
+                    .request_async(&self.reqwest_client)
+                    .await?)
+            })
             .await?;
 
         let access_token = token_result.extra_fields().id_token.clone();
@@ -161,11 +222,19 @@ impl OAuth2Client for GoogleOAuth2Client {
     async fn exchange_code(
         &self,
         code: String,
+        redirect_url: Option<RedirectUrl>,
     ) -> std::result::Result<OAuth2Response, TapLockError> {
         let token_result = self
-            .client
-            .exchange_code(AuthorizationCode::new(code))
-            .request_async(&self.reqwest_client)
+            .retry
+            .token_request(|| async {
+                let mut request = self
+                    .client
+                    .exchange_code(AuthorizationCode::new(code.clone()));
+                if let Some(redirect_url) = &redirect_url {
+                    request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+                }
+                Ok(request.request_async(&self.reqwest_client).await?)
+            })
             .await?;
 
         let access_token = token_result.extra_fields().id_token.clone();
@@ -184,14 +253,18 @@ impl OAuth2Client for GoogleOAuth2Client {
         let response = decode_access_token(self, access_token)?;
         Ok(response)
     }
-    fn get_authorization_url(&self) -> String {
-        let (auth_url, _csrf_token) = self
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String {
+        let mut request = self
             .client
-            .authorize_url(CsrfToken::new_random)
-            .add_extra_param("access_type", "offline")
-            .add_extra_param("prompt", "consent")
-            .add_scopes(["openid", "email", "profile"].map(|s| Scope::new(s.into())))
-            .url();
+            .authorize_url(|| options.csrf_token())
+            .add_scopes(self.scopes.iter().cloned());
+        if let Some(redirect_url) = &options.redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        for (name, value) in options.merge_into(&self.auth_params) {
+            request = request.add_extra_param(name, value);
+        }
+        let (auth_url, _csrf_token) = request.url();
         auth_url.to_string()
     }
 }
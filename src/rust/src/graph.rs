@@ -0,0 +1,425 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::TapLockError;
+use crate::retry::RetryPolicy;
+
+pub(crate) const GRAPH_URL: &str = "https://graph.microsoft.com";
+
+// The application id of Microsoft Graph, which v1 tokens use as `aud`
+const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
+
+// How long the groups of a user are remembered. They are fetched again
+// on every login and token refresh, so this only bounds how long the
+// groups of users that stopped using the app are kept
+const GROUPS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Expired groups are swept at most this often, so that lookups stay
+// cheap while users that stopped using the app are still forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long Graph is not asked again for the groups of a user after it
+// failed. Tokens with unknown groups are refreshed on every request, so
+// an outage would otherwise make every request wait for Graph
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+
+enum Groups {
+    Resolved(Vec<String>),
+    /// The message of the error Graph failed with
+    Failed(String),
+}
+
+// Group ids of each user, or the last failure to fetch them, with the
+// time they were fetched
+struct GroupCache {
+    users: HashMap<String, (Groups, Instant)>,
+    ttl: Duration,
+    pruned_at: Instant,
+}
+
+impl GroupCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            users: HashMap::new(),
+            ttl,
+            pruned_at: Instant::now(),
+        }
+    }
+
+    fn is_fresh(ttl: Duration, (groups, fetched_at): &(Groups, Instant)) -> bool {
+        let ttl = match groups {
+            Groups::Resolved(_) => ttl,
+            Groups::Failed(_) => ttl.min(FAILURE_TTL),
+        };
+        fetched_at.elapsed() < ttl
+    }
+
+    fn get(&mut self, user: &str) -> Option<&Groups> {
+        self.prune();
+        if !Self::is_fresh(self.ttl, self.users.get(user)?) {
+            self.users.remove(user);
+            return None;
+        }
+        self.users.get(user).map(|(groups, _)| groups)
+    }
+
+    fn insert(&mut self, user: &str, groups: Groups) {
+        self.prune();
+        self.users
+            .insert(user.to_string(), (groups, Instant::now()));
+    }
+
+    fn prune(&mut self) {
+        if self.pruned_at.elapsed() < PRUNE_INTERVAL {
+            return;
+        }
+        let ttl = self.ttl;
+        self.users.retain(|_, entry| Self::is_fresh(ttl, entry));
+        self.pruned_at = Instant::now();
+    }
+}
+
+#[derive(Deserialize)]
+struct MemberObjects {
+    value: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct OnBehalfOfResponse {
+    access_token: String,
+}
+
+// The app exchanging the tokens of its users for Graph tokens
+#[derive(Clone)]
+struct OnBehalfOf {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+}
+
+// The `aud` claim of a JWT, read without checking the signature. The
+// token is only forwarded to Graph, which checks it
+fn audience(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    Some(claims.get("aud")?.as_str()?.to_string())
+}
+
+/// Whether Entra ID left the `groups` claim out because the user is
+/// in too many groups. The groups then have to be fetched from Graph.
+pub(crate) fn has_group_overage(claims: &serde_json::Value) -> bool {
+    claims
+        .get("_claim_names")
+        .and_then(|names| names.get("groups"))
+        .is_some()
+        || claims.get("hasgroups").and_then(|v| v.as_bool()) == Some(true)
+}
+
+/// Resolves the groups of users through Microsoft Graph and remembers
+/// them per user, so that tokens decoded later get their groups back.
+#[derive(Clone)]
+pub struct GraphClient {
+    base_url: String,
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    on_behalf_of: Option<OnBehalfOf>,
+    groups: Arc<Mutex<GroupCache>>,
+}
+
+impl GraphClient {
+    pub fn new(base_url: &str, client: reqwest::Client, retry: RetryPolicy) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+            retry,
+            on_behalf_of: None,
+            groups: Arc::new(Mutex::new(GroupCache::new(GROUPS_TTL))),
+        }
+    }
+
+    /// Exchanges access tokens that are not for Graph, like the ones
+    /// for the app itself requested by default, for Graph tokens through
+    /// the on-behalf-of flow of the token endpoint of the app.
+    pub fn on_behalf_of(mut self, token_url: &str, client_id: &str, client_secret: &str) -> Self {
+        self.on_behalf_of = Some(OnBehalfOf {
+            token_url: token_url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+        });
+        self
+    }
+
+    fn is_graph_token(&self, access_token: &str) -> bool {
+        audience(access_token).is_some_and(|aud| {
+            let aud = aud.trim_end_matches('/');
+            aud == GRAPH_URL || aud == GRAPH_APP_ID || aud == self.base_url
+        })
+    }
+
+    // An access token Graph accepts, the given one if it already is
+    async fn graph_token(&self, access_token: &str) -> Result<String, TapLockError> {
+        let Some(app) = self
+            .on_behalf_of
+            .as_ref()
+            .filter(|_| !self.is_graph_token(access_token))
+        else {
+            return Ok(access_token.to_string());
+        };
+        let scope = format!("{}/.default", self.base_url);
+        let response = self
+            .retry
+            .run(TapLockError::is_transient, || async {
+                Ok(self
+                    .client
+                    .post(&app.token_url)
+                    .form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("client_id", &app.client_id),
+                        ("client_secret", &app.client_secret),
+                        ("assertion", access_token),
+                        ("scope", &scope),
+                        ("requested_token_use", "on_behalf_of"),
+                    ])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<OnBehalfOfResponse>()
+                    .await?)
+            })
+            .await?;
+        Ok(response.access_token)
+    }
+
+    fn groups(&self) -> std::sync::MutexGuard<'_, GroupCache> {
+        self.groups
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The groups last fetched for `user`, or the error fetching them
+    /// failed with, if they are still fresh.
+    pub fn cached_groups(&self, user: &str) -> Option<Result<Vec<String>, TapLockError>> {
+        match self.groups().get(user)? {
+            Groups::Resolved(ids) => Some(Ok(ids.clone())),
+            Groups::Failed(message) => Some(Err(TapLockError::new(message.clone()))),
+        }
+    }
+
+    /// Fetches the groups of the signed-in user with their access
+    /// token and remembers them for `user`. After a failure, Graph is
+    /// not asked again for a while and the same error is returned.
+    pub async fn fetch_groups(
+        &self,
+        user: &str,
+        access_token: &str,
+    ) -> Result<Vec<String>, TapLockError> {
+        if let Some(Groups::Failed(message)) = self.groups().get(user) {
+            return Err(TapLockError::new(message.clone()));
+        }
+        let result = self.request_groups(access_token).await;
+
+        let groups = match &result {
+            Ok(response) => Groups::Resolved(response.value.clone()),
+            Err(err) => Groups::Failed(err.to_string()),
+        };
+        self.groups().insert(user, groups);
+        Ok(result?.value)
+    }
+
+    async fn request_groups(&self, access_token: &str) -> Result<MemberObjects, TapLockError> {
+        let access_token = self.graph_token(access_token).await?;
+        let url = format!("{}/v1.0/me/getMemberObjects", self.base_url);
+        self.retry
+            .run(TapLockError::is_transient, || async {
+                Ok(self
+                    .client
+                    .post(&url)
+                    .bearer_auth(&access_token)
+                    .json(&serde_json::json!({ "securityEnabledOnly": false }))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<MemberObjects>()
+                    .await?)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    fn jwt(claims: serde_json::Value) -> String {
+        format!("e30.{}.c2ln", URL_SAFE_NO_PAD.encode(claims.to_string()))
+    }
+
+    // Answers each request with the response `respond` gives for its
+    // request line, headers and body, and stops after `requests`
+    fn serve(
+        requests: usize,
+        respond: impl Fn(&str, &str, &str) -> String + Send + 'static,
+    ) -> (String, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    headers.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body = respond(&request_line, &headers, &String::from_utf8(body).unwrap());
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        (url, server)
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn fetches_groups_with_tokens_for_the_app() {
+        // The default scopes give a token for the app, not for Graph
+        let app_token = jwt(serde_json::json!({ "aud": "client-id" }));
+        let graph_token = jwt(serde_json::json!({ "aud": GRAPH_URL }));
+        let expected_assertion = format!("assertion={app_token}");
+        let expected_bearer = format!("Bearer {graph_token}");
+        let (url, server) = serve(2, move |request, headers, body| {
+            if request.starts_with("POST /token ") {
+                assert!(body.contains("requested_token_use=on_behalf_of"));
+                assert!(body.contains(&expected_assertion));
+                serde_json::json!({ "access_token": graph_token }).to_string()
+            } else {
+                assert!(request.starts_with("POST /v1.0/me/getMemberObjects "));
+                assert!(headers.contains(&expected_bearer));
+                serde_json::json!({ "value": ["a", "b"] }).to_string()
+            }
+        });
+
+        let graph = GraphClient::new(&url, reqwest::Client::new(), RetryPolicy::default())
+            .on_behalf_of(&format!("{url}/token"), "client-id", "secret");
+        let groups = block_on(graph.fetch_groups("tenant/alice", &app_token)).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(groups, ids(&["a", "b"]));
+        assert!(matches!(
+            graph.cached_groups("tenant/alice"),
+            Some(Ok(groups)) if groups == ids(&["a", "b"])
+        ));
+    }
+
+    #[test]
+    fn uses_graph_tokens_as_they_are() {
+        let graph = GraphClient::new(GRAPH_URL, reqwest::Client::new(), RetryPolicy::default())
+            .on_behalf_of("https://login.example.com/token", "client-id", "secret");
+        assert!(graph.is_graph_token(&jwt(serde_json::json!({ "aud": GRAPH_APP_ID }))));
+        assert!(graph.is_graph_token(&jwt(
+            serde_json::json!({ "aud": "https://graph.microsoft.com/" })
+        )));
+        assert!(!graph.is_graph_token(&jwt(serde_json::json!({ "aud": "client-id" }))));
+        assert!(!graph.is_graph_token("opaque"));
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn resolved(cache: &mut GroupCache, user: &str) -> Option<Vec<String>> {
+        match cache.get(user)? {
+            Groups::Resolved(ids) => Some(ids.clone()),
+            Groups::Failed(_) => None,
+        }
+    }
+
+    #[test]
+    fn remembers_groups_until_they_expire() {
+        let mut cache = GroupCache::new(GROUPS_TTL);
+        cache.insert("tenant/alice", Groups::Resolved(ids(&["a", "b"])));
+        assert_eq!(resolved(&mut cache, "tenant/alice"), Some(ids(&["a", "b"])));
+        assert!(cache.get("tenant/bob").is_none());
+
+        let mut cache = GroupCache::new(Duration::ZERO);
+        cache.insert("tenant/alice", Groups::Resolved(ids(&["a"])));
+        assert!(cache.get("tenant/alice").is_none());
+        assert!(cache.users.is_empty());
+    }
+
+    #[test]
+    fn remembers_failures_for_a_short_while() {
+        let mut cache = GroupCache::new(GROUPS_TTL);
+        cache.insert("tenant/alice", Groups::Failed("forbidden".to_string()));
+        assert!(matches!(
+            cache.get("tenant/alice"),
+            Some(Groups::Failed(message)) if message == "forbidden"
+        ));
+
+        let fetched_at = Instant::now().checked_sub(FAILURE_TTL);
+        if let (Some(fetched_at), Some(entry)) = (fetched_at, cache.users.get_mut("tenant/alice")) {
+            entry.1 = fetched_at;
+        }
+        assert!(cache.get("tenant/alice").is_none());
+    }
+
+    #[test]
+    fn prunes_expired_groups_on_lookups() {
+        let mut cache = GroupCache::new(Duration::ZERO);
+        cache.insert("tenant/alice", Groups::Resolved(ids(&["a"])));
+        cache.insert("tenant/bob", Groups::Resolved(ids(&["b"])));
+        // Not swept more than once per interval
+        cache.get("tenant/carol");
+        assert_eq!(cache.users.len(), 2);
+
+        cache.pruned_at = Instant::now()
+            .checked_sub(PRUNE_INTERVAL)
+            .unwrap_or(cache.pruned_at);
+        cache.get("tenant/carol");
+        assert!(cache.users.is_empty());
+    }
+
+    #[test]
+    fn detects_group_overage() {
+        let overage = serde_json::json!({
+            "_claim_names": { "groups": "src1" },
+            "_claim_sources": { "src1": { "endpoint": "https://graph.windows.net" } }
+        });
+        assert!(has_group_overage(&overage));
+        assert!(has_group_overage(&serde_json::json!({ "hasgroups": true })));
+        assert!(!has_group_overage(&serde_json::json!({ "groups": ["a"] })));
+    }
+}
@@ -4,6 +4,25 @@ use std::time::{Duration, Instant};
 use tokio::sync::Mutex as AsyncMutex;
 
 use crate::error::TapLockError;
+use crate::retry::RetryPolicy;
+
+async fn fetch_jwks(
+    client: &reqwest::Client,
+    url: &str,
+    retry: &RetryPolicy,
+) -> Result<JwkSet, TapLockError> {
+    retry
+        .run(TapLockError::is_transient, || async {
+            Ok(client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<JwkSet>()
+                .await?)
+        })
+        .await
+}
 
 #[derive(Clone)]
 pub struct JwksClient {
@@ -11,6 +30,7 @@ pub struct JwksClient {
     // Use std::sync::RwLock for fast, synchronous reads during validation
     jwks: Arc<RwLock<JwkSet>>,
     client: reqwest::Client,
+    retry: RetryPolicy,
     // Async mutex to ensure only one thread performs the network refresh
     refresh_lock: Arc<AsyncMutex<()>>,
     // Track last refresh to prevent spam
@@ -18,14 +38,19 @@ pub struct JwksClient {
 }
 
 impl JwksClient {
-    pub async fn new(url: String, client: reqwest::Client) -> Result<Self, TapLockError> {
+    pub async fn new(
+        url: String,
+        client: reqwest::Client,
+        retry: RetryPolicy,
+    ) -> Result<Self, TapLockError> {
         // Initial fetch
-        let jwks = client.get(&url).send().await?.json::<JwkSet>().await?;
+        let jwks = fetch_jwks(&client, &url, &retry).await?;
 
         Ok(Self {
             url,
             jwks: Arc::new(RwLock::new(jwks)),
             client,
+            retry,
             refresh_lock: Arc::new(AsyncMutex::new(())),
             last_updated: Arc::new(Mutex::new(Instant::now())),
         })
@@ -62,13 +87,7 @@ impl JwksClient {
         }
 
         // 5. Perform the network request
-        let new_jwks = self
-            .client
-            .get(&self.url)
-            .send()
-            .await?
-            .json::<JwkSet>()
-            .await?;
+        let new_jwks = fetch_jwks(&self.client, &self.url, &self.retry).await?;
 
         // 6. Update the cache
         let found_key = new_jwks.find(kid).cloned();
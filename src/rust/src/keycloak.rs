@@ -1,17 +1,21 @@
-use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use jsonwebtoken::{decode, decode_header, DecodingKey};
 use oauth2::TokenResponse;
 use oauth2::{
     basic::{
         BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
         BasicTokenType,
     },
-    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, RedirectUrl, Scope,
+    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, RedirectUrl, Scope,
     StandardRevocableToken, StandardTokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 use crate::error::TapLockError;
 use crate::jwks::JwksClient;
+use crate::options::{AuthorizationUrlOptions, RuntimeOptions};
+use crate::retry::RetryPolicy;
+use crate::validation::TokenValidation;
 use crate::{OAuth2Client, OAuth2Response};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -41,6 +45,11 @@ pub struct KeycloakOAuth2Client {
     client_id: String,
     jwks_client: JwksClient,
     use_refresh_token: bool,
+    prefix_client_roles: bool,
+    retry: RetryPolicy,
+    validation: TokenValidation,
+    scopes: Vec<Scope>,
+    auth_params: Vec<(String, String)>,
 }
 
 impl KeycloakOAuth2Client {
@@ -49,6 +58,39 @@ impl KeycloakOAuth2Client {
     }
 }
 
+// Keycloak keeps realm roles under `realm_access.roles` and client
+// roles under `resource_access.<client>.roles`. They are gathered into
+// a flat `roles` claim, after any roles already in the token. Without
+// `prefix_client_roles` only the roles of our own client are added, so
+// that a role of another client cannot pass for one of ours.
+fn normalize_roles(client: &KeycloakOAuth2Client, claims: &mut serde_json::Value) {
+    fn role_names(access: Option<&serde_json::Value>) -> impl Iterator<Item = &str> {
+        access
+            .and_then(|access| access.get("roles"))
+            .and_then(|roles| roles.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|role| role.as_str())
+    }
+
+    let mut roles: Vec<String> = role_names(Some(&*claims)).map(str::to_string).collect();
+    roles.extend(role_names(claims.get("realm_access")).map(str::to_string));
+    if let Some(resource_access) = claims.get("resource_access").and_then(|r| r.as_object()) {
+        for (resource, access) in resource_access {
+            if client.prefix_client_roles {
+                roles.extend(role_names(Some(access)).map(|role| format!("{resource}:{role}")));
+            } else if *resource == client.client_id {
+                roles.extend(role_names(Some(access)).map(str::to_string));
+            }
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    roles.retain(|role| seen.insert(role.clone()));
+    if let Some(claims) = claims.as_object_mut() {
+        claims.insert("roles".to_string(), roles.into());
+    }
+}
+
 fn decode_access_token(
     client: &KeycloakOAuth2Client,
     access_token: String,
@@ -58,18 +100,23 @@ fn decode_access_token(
     let kid = jwt_header.kid.ok_or(TapLockError::KidNotFound)?;
     let algo = jwt_header.alg;
     let decoding_key = client.get_jwk(&kid).ok_or(TapLockError::KidNotFound)?;
-    let mut validation = Validation::new(algo);
-    validation.set_audience(&[&client.client_id]);
+    let validation = client.validation.jwt_validation(algo, &client.client_id);
     let val = decode::<serde_json::Value>(
         token_trim,
         &DecodingKey::from_jwk(&decoding_key)?,
         &validation,
     )?;
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    let mut fields = val.claims;
+    normalize_roles(client, &mut fields);
 
     Ok(OAuth2Response {
         access_token,
         refresh_token: None,
-        fields: val.claims,
+        fields,
+        warnings: Vec::new(),
     })
 }
 
@@ -83,18 +130,23 @@ async fn decode_token_and_maybe_refresh_jwks(
 
     let decoding_key = client.jwks_client.get_key_with_refresh(&kid).await?;
     let algo = jwt_header.alg;
-    let mut validation = Validation::new(algo);
-    validation.set_audience(&[&client.client_id]);
+    let validation = client.validation.jwt_validation(algo, &client.client_id);
     let val = decode::<serde_json::Value>(
         token_trim,
         &DecodingKey::from_jwk(&decoding_key)?,
         &validation,
     )?;
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    let mut fields = val.claims;
+    normalize_roles(client, &mut fields);
 
     Ok(OAuth2Response {
         access_token,
         refresh_token: None,
-        fields: val.claims,
+        fields,
+        warnings: Vec::new(),
     })
 }
 
@@ -105,23 +157,22 @@ pub async fn build_oauth2_state_keycloak(
     base_url: &str,
     realm: &str,
     use_refresh_token: bool,
+    options: &RuntimeOptions,
 ) -> std::result::Result<KeycloakOAuth2Client, TapLockError> {
     let base_url = base_url.trim_end_matches('/');
     let auth_url = format!("{base_url}/realms/{realm}/protocol/openid-connect/auth");
     let token_url = format!("{base_url}/realms/{realm}/protocol/openid-connect/token");
     let jwks_url = format!("{base_url}/realms/{realm}/protocol/openid-connect/certs");
-    let app_url = app_url.trim_end_matches('/');
-    let redirect_url = format!("{app_url}/login");
 
     let client = Client::new(ClientId::new(client_id.to_string()))
         .set_client_secret(ClientSecret::new(client_secret.to_string()))
         .set_auth_uri(AuthUrl::new(auth_url)?)
         .set_token_uri(TokenUrl::new(token_url)?)
-        .set_redirect_uri(RedirectUrl::new(redirect_url)?);
+        .set_redirect_uri(options.redirect_url(app_url)?);
 
     let reqwest_client = reqwest::Client::new();
 
-    let jwks_client = JwksClient::new(jwks_url, reqwest_client.clone()).await?;
+    let jwks_client = JwksClient::new(jwks_url, reqwest_client.clone(), options.retry).await?;
 
     Ok(KeycloakOAuth2Client {
         reqwest_client,
@@ -129,6 +180,13 @@ pub async fn build_oauth2_state_keycloak(
         jwks_client,
         client_id: client_id.to_string(),
         use_refresh_token,
+        prefix_client_roles: options.prefix_client_roles,
+        retry: options.retry,
+        validation: options.validation.clone(),
+        scopes: options.scopes_or(&["openid", "email", "profile", "offline_access"]),
+        // Keycloak issues refresh tokens with the `offline_access` scope,
+        // so consent is only asked if the client requires it
+        auth_params: options.auth_params_or(&[]),
     })
 }
 
@@ -142,12 +200,15 @@ impl OAuth2Client for KeycloakOAuth2Client {
             return Err(TapLockError::new("Refresh token is disabled"));
         }
         let token_result = self
-            .client
-            .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
-            .add_scopes(
-                ["openid", "email", "profile", "offline_access"].map(|s| Scope::new(s.into())),
-            )
-            .request_async(&self.reqwest_client)
+            .retry
+            .token_request(|| async {
+                Ok(self
+                    .client
+                    .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+                    .add_scopes(self.scopes.iter().cloned())
+                    .request_async(&self.reqwest_client)
+                    .await?)
+            })
             .await?;
 
         let access_token = token_result.extra_fields().id_token.clone();
@@ -165,11 +226,19 @@ impl OAuth2Client for KeycloakOAuth2Client {
     async fn exchange_code(
         &self,
         code: String,
+        redirect_url: Option<RedirectUrl>,
     ) -> std::result::Result<OAuth2Response, TapLockError> {
         let token_result = self
-            .client
-            .exchange_code(AuthorizationCode::new(code))
-            .request_async(&self.reqwest_client)
+            .retry
+            .token_request(|| async {
+                let mut request = self
+                    .client
+                    .exchange_code(AuthorizationCode::new(code.clone()));
+                if let Some(redirect_url) = &redirect_url {
+                    request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+                }
+                Ok(request.request_async(&self.reqwest_client).await?)
+            })
             .await?;
 
         let access_token = token_result.extra_fields().id_token.clone();
@@ -188,16 +257,18 @@ impl OAuth2Client for KeycloakOAuth2Client {
         let response = decode_access_token(self, access_token)?;
         Ok(response)
     }
-    fn get_authorization_url(&self) -> String {
-        let (auth_url, _csrf_token) = self
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String {
+        let mut request = self
             .client
-            .authorize_url(CsrfToken::new_random)
-            .add_extra_param("access_type", "offline")
-            .add_extra_param("prompt", "consent")
-            .add_scopes(
-                ["openid", "email", "profile", "offline_access"].map(|s| Scope::new(s.into())),
-            )
-            .url();
+            .authorize_url(|| options.csrf_token())
+            .add_scopes(self.scopes.iter().cloned());
+        if let Some(redirect_url) = &options.redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        for (name, value) in options.merge_into(&self.auth_params) {
+            request = request.add_extra_param(name, value);
+        }
+        let (auth_url, _csrf_token) = request.url();
         auth_url.to_string()
     }
 }
@@ -1,14 +1,41 @@
+mod apple;
+mod auth0;
+mod callback;
+mod cognito;
 mod cookies;
 mod entra_id;
 mod error;
+mod generic;
+mod github;
+mod gitlab;
 mod google;
+mod graph;
 mod jwks;
 mod keycloak;
+mod oidc;
+mod okta;
+mod options;
+mod policy;
+mod proxy;
+mod refresh;
+mod retry;
+mod seal;
+mod session;
+mod state;
+mod token_cache;
+mod userinfo;
+mod validation;
 use extendr_api::prelude::*;
 use std::sync::Arc;
 use tokio::sync::oneshot::{self, error::TryRecvError};
 
+use crate::cookies::CookieBuilder;
 use crate::error::TapLockError;
+use crate::options::{AuthorizationUrlOptions, GenericProviderOptions, RuntimeOptions};
+use crate::proxy::ForwardedRequest;
+use crate::refresh::RefreshGroup;
+use crate::session::{Session, SessionTimes, Sessions};
+use crate::token_cache::{Cached, TokenCache};
 
 #[extendr]
 enum FutureResult {
@@ -51,6 +78,8 @@ pub(crate) struct OAuth2Response {
     pub access_token: String,
     pub refresh_token: Option<String>,
     pub fields: serde_json::Value,
+    /// Problems that did not prevent the login, raised as R warnings
+    pub warnings: Vec<String>,
 }
 
 fn from_json_value_to_robj(value: &serde_json::Value) -> Robj {
@@ -81,31 +110,54 @@ fn from_json_value_to_robj(value: &serde_json::Value) -> Robj {
     }
 }
 
+// The tokens are left out, they are only handed to R through
+// `get_session`
+fn session_info(session: &Session) -> Robj {
+    list!(
+        id = session.id.clone(),
+        subject = session.subject.clone(),
+        created_at = session.created_at as f64,
+        last_seen = session.last_seen as f64
+    )
+    .into()
+}
+
 impl IntoRobj for &OAuth2Response {
     fn into_robj(self) -> Robj {
         let fields = from_json_value_to_robj(&self.fields);
         list!(
             access_token = self.access_token.clone(),
             refresh_token = self.refresh_token.clone(),
-            fields = fields
+            fields = fields,
+            warnings = self.warnings.clone()
         )
         .into()
     }
 }
 
+pub(crate) type TokenResult = std::result::Result<OAuth2Response, TapLockError>;
+
+// The result is shared since concurrent refreshes of the same token
+// resolve to the same response (see `RefreshGroup`)
 #[extendr]
 struct AsyncFuture {
-    rx: oneshot::Receiver<std::result::Result<OAuth2Response, TapLockError>>,
+    rx: oneshot::Receiver<Arc<TokenResult>>,
 }
 
 #[extendr]
 impl AsyncFuture {
     fn poll(&mut self) -> FutureResult {
         match self.rx.try_recv() {
-            Ok(Ok(robj)) => FutureResult::Ready(robj.into_robj()),
-            Ok(Err(err)) => FutureResult::Error(err.into_robj()),
+            Ok(result) => match result.as_ref() {
+                Ok(response) => FutureResult::Ready(response.into_robj()),
+                Err(err) => FutureResult::Error(err.into_robj()),
+            },
             Err(TryRecvError::Empty) => FutureResult::Pending,
-            Err(e) => panic!("{e}"),
+            // The task panicked or the runtime shut down before it sent
+            // a result
+            Err(TryRecvError::Closed) => {
+                FutureResult::Error(TapLockError::new("The request was cancelled").into_robj())
+            }
         }
     }
 }
@@ -121,64 +173,443 @@ pub(crate) trait OAuth2Client: Send + Sync {
     async fn exchange_code(
         &self,
         code: String,
+        redirect_url: Option<oauth2::RedirectUrl>,
     ) -> std::result::Result<OAuth2Response, TapLockError>;
+    // Providers that only describe the user in the callback, as Apple
+    // does on the first login, add those details to the new token
+    fn add_callback_user(&self, _response: &mut OAuth2Response, _user: &str) {}
     fn decode_access_token(
         &self,
         access_token: String,
     ) -> std::result::Result<OAuth2Response, TapLockError>;
-    fn get_authorization_url(&self) -> String;
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String;
 }
 
+// Holds the session times when tokens are kept in cookies
+const SESSION_COOKIE: &str = "session";
+
+// Holds the nonce of the `state` of a login in progress, for as long
+// as the user may take to log in
+const LOGIN_STATE_COOKIE: &str = "login_state";
+const LOGIN_STATE_MAX_AGE: u64 = 600;
+
+// Holds the parameters of a callback POSTed by the provider until the
+// browser comes back to the callback path with a GET
+const CALLBACK_COOKIE: &str = "login_callback";
+const CALLBACK_MAX_AGE: u64 = 60;
+
 #[extendr]
 struct OAuth2Runtime {
     runtime: tokio::runtime::Runtime,
     client: Arc<dyn OAuth2Client>,
-    app_url: Robj,
+    app_url: String,
+    options: RuntimeOptions,
+    cookies: CookieBuilder,
+    sessions: Option<Sessions>,
+    refreshes: Arc<RefreshGroup>,
+    token_cache: TokenCache,
+}
+
+impl OAuth2Runtime {
+    fn new(
+        runtime: tokio::runtime::Runtime,
+        client: Arc<dyn OAuth2Client>,
+        app_url: &str,
+        options: RuntimeOptions,
+    ) -> std::result::Result<Self, TapLockError> {
+        let cookies = options.cookies.resolve(app_url)?;
+        let sessions = options.sessions.open()?;
+        // Without a session store the login time is kept in a cookie,
+        // which must be sealed so that it cannot be forged
+        if sessions.is_none()
+            && options.sessions.timeouts.is_enabled()
+            && options.cookies.keys.is_none()
+        {
+            return Err(TapLockError::new(
+                "Session timeouts require either `session_store` or `cookie_keys`",
+            ));
+        }
+        Ok(OAuth2Runtime {
+            runtime,
+            client,
+            app_url: app_url.trim_end_matches('/').to_string(),
+            token_cache: TokenCache::new(options.token_cache_size),
+            options,
+            cookies,
+            sessions,
+            refreshes: Arc::new(RefreshGroup::default()),
+        })
+    }
+
+    // Ask the provider to re-authenticate users that logged in longer
+    // ago than the absolute timeout, unless requested otherwise. The
+    // state carries `nonce`, see `start_login`
+    fn authorization_url(&self, mut options: AuthorizationUrlOptions, nonce: &str) -> String {
+        options.state = Some(state::new_state(nonce, options.return_to.as_deref()));
+        options.response_mode = self.options.response_mode.clone();
+        if options.max_age.is_none() {
+            options.max_age = self
+                .options
+                .sessions
+                .timeouts
+                .absolute
+                .map(|absolute| absolute.as_secs());
+        }
+        self.client.get_authorization_url(&options)
+    }
+
+    // Session times are only tracked in a cookie when there is no
+    // session store, which enforces the timeouts itself
+    fn tracks_session_cookie(&self) -> bool {
+        self.sessions.is_none() && self.options.sessions.timeouts.is_enabled()
+    }
+
+    fn session_cookie(
+        &self,
+        times: SessionTimes,
+        cookie_string: Option<&str>,
+    ) -> std::result::Result<Vec<String>, TapLockError> {
+        let max_age = self
+            .options
+            .sessions
+            .timeouts
+            .remaining(times.created_at, session::now());
+        let existing = self.cookies.names(cookie_string.unwrap_or(""));
+        self.cookies
+            .build(SESSION_COOKIE, &times.to_string(), max_age, &existing)
+    }
+
+    fn sessions(&self) -> std::result::Result<&Sessions, TapLockError> {
+        self.sessions.as_ref().ok_or_else(|| {
+            TapLockError::new(
+                "Sessions are not enabled, see `session_store` in `runtime_options()`",
+            )
+        })
+    }
+
+    // The redirect URL only needs to be overridden when the app is
+    // reached through a different public URL than the configured one
+    fn redirect_url_for(
+        &self,
+        app_url: &str,
+    ) -> std::result::Result<Option<oauth2::RedirectUrl>, TapLockError> {
+        if app_url.trim_end_matches('/') == self.app_url {
+            return Ok(None);
+        }
+        self.options.redirect_url(app_url).map(Some)
+    }
 }
 
 #[extendr]
 impl OAuth2Runtime {
     // Should return a AsyncFuture with a List containing the access_token
-    // and the refresh token
-    fn request_token(&self, authorization_code: String) -> AsyncFuture {
+    // and the refresh token. `app_url` is the public URL the login was
+    // started from (see `resolve_app_url`) and `user` the `user` field
+    // of the callback, if any
+    fn request_token(
+        &self,
+        authorization_code: String,
+        app_url: &str,
+        user: Option<&str>,
+    ) -> AsyncFuture {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let client = Arc::clone(&self.client);
+        let redirect_url = self.redirect_url_for(app_url);
+        let user = user.map(str::to_string);
         self.runtime.spawn(async move {
-            let response = client.exchange_code(authorization_code).await;
-            let _ = tx.send(response);
+            let response = match redirect_url {
+                Ok(redirect_url) => client.exchange_code(authorization_code, redirect_url).await,
+                Err(e) => Err(e),
+            };
+            let response = response.map(|mut response| {
+                if let Some(user) = &user {
+                    client.add_callback_user(&mut response, user);
+                }
+                response
+            });
+            let _ = tx.send(Arc::new(response));
         });
         AsyncFuture { rx }
     }
 
     // Should return a AsyncFuture with a List containing the new access_token
-    // and the refresh token
+    // and the refresh token. Concurrent requests for the same refresh
+    // token share a single call to the provider
     fn request_token_refresh(&self, refresh_token: String) -> AsyncFuture {
-        let (tx, rx) = tokio::sync::oneshot::channel();
         let client = Arc::clone(&self.client);
-        self.runtime.spawn(async move {
-            let response = client.exchange_refresh_token(refresh_token).await;
-            let _ = tx.send(response);
-        });
+        let token = refresh_token.clone();
+        let rx = self
+            .refreshes
+            .join(&self.runtime, &refresh_token, move || async move {
+                client.exchange_refresh_token(token).await
+            });
         AsyncFuture { rx }
     }
 
     // Should return a list with the deocoded token in the form of a list
-    // or an error if the token is invalid
-    fn decode_token(&self, token: String) -> Result<Robj> {
-        let res = self
-            .client
-            .decode_access_token(token)
-            .map_err(|_| Error::from("Hello".to_string()))?;
-        Ok(res.into_robj())
+    // or an error if the token is invalid. Tokens that were already
+    // verified (or rejected) are answered from the token cache. The
+    // `expiring` element is `TRUE` within `refresh_ahead` of expiry.
+    // With a `path`, the `forbidden` element holds the first policy
+    // applying to it that the token fails (see `PolicyRules`)
+    fn decode_token(&self, token: String, path: Option<&str>) -> Result<Robj> {
+        let key = token.trim_start_matches("Bearer").trim();
+        let validation = &self.options.validation;
+        let now = validation.clock.now();
+        let fields = match self.token_cache.get(key, now) {
+            Some(Cached::Valid(claims)) => claims,
+            Some(Cached::Rejected(err)) => return Err(err.into()),
+            None => {
+                let result = self.client.decode_access_token(token.clone());
+                // Claims decoded with warnings are incomplete, they are
+                // decoded again on the next request
+                let complete = result.as_ref().map_or(true, |res| res.warnings.is_empty());
+                let result = result.map(|res| res.fields);
+                if complete {
+                    let valid_until = result
+                        .as_ref()
+                        .ok()
+                        .and_then(|claims| validation.valid_until(claims));
+                    self.token_cache.insert(key, &result, valid_until);
+                }
+                result?
+            }
+        };
+        // Lets the middleware refresh the token before it expires
+        let expiring = fields
+            .get("exp")
+            .and_then(|exp| exp.as_f64())
+            .is_some_and(|exp| exp - (now as f64) <= self.options.refresh_ahead.as_secs_f64());
+        let forbidden = path
+            .and_then(|path| self.options.policies.check(path, &fields))
+            .map(|policy| policy.source().to_string());
+        Ok(list!(
+            access_token = token,
+            refresh_token = NULL,
+            fields = from_json_value_to_robj(&fields),
+            expiring = expiring,
+            forbidden = forbidden
+        )
+        .into())
+    }
+
+    // Starts a login: returns the authorization URL, with per-request
+    // parameters such as `login_hint` or `prompt`, for an app reached
+    // through `app_url`, and the cookie binding its `state` to the
+    // browser that sent `cookie_string`
+    fn start_login(
+        &self,
+        options: List,
+        app_url: &str,
+        cookie_string: Option<&str>,
+    ) -> Result<List> {
+        let mut options = AuthorizationUrlOptions::try_from(options)?;
+        options.redirect_url = self.redirect_url_for(app_url)?;
+        let nonce = state::new_nonce();
+        let existing = self.cookies.names(cookie_string.unwrap_or(""));
+        let cookies = self.cookies.cross_site().build(
+            LOGIN_STATE_COOKIE,
+            &nonce,
+            Some(LOGIN_STATE_MAX_AGE),
+            &existing,
+        )?;
+        Ok(list!(
+            url = self.authorization_url(options, &nonce),
+            cookies = cookies
+        ))
     }
 
-    fn get_authorization_url(&self) -> String {
-        self.client.get_authorization_url()
+    // Checks that the `state` sent back to the callback belongs to a
+    // login started by the browser that sent `cookie_string`, so that
+    // a login (and its return path) cannot be forced on a user
+    fn check_login_state(&self, state: Option<&str>, cookie_string: Option<&str>) -> bool {
+        let Some(state) = state else {
+            return false;
+        };
+        self.cookies
+            .parse(cookie_string.unwrap_or(""))
+            .get(LOGIN_STATE_COOKIE)
+            .is_some_and(|nonce| state::verify(state, nonce))
     }
 
-    fn get_app_url(&self) -> Robj {
+    // Builds the cookie keeping the `body` of a callback POSTed by the
+    // provider (`response_mode=form_post`). That cross-site POST only
+    // comes with the `login_state` cookie, so the login is finished by
+    // the GET it is redirected to, which comes with every cookie and can
+    // replace those of an earlier login
+    fn stash_callback(&self, body: &str, cookie_string: Option<&str>) -> Result<Vec<String>> {
+        let existing = self.cookies.names(cookie_string.unwrap_or(""));
+        Ok(self.cookies.cross_site().build(
+            CALLBACK_COOKIE,
+            body,
+            Some(CALLBACK_MAX_AGE),
+            &existing,
+        )?)
+    }
+
+    fn get_app_url(&self) -> String {
         self.app_url.clone()
     }
+
+    // Returns the public URL of the app for a request. Only differs from
+    // `get_app_url` when the request comes from a trusted proxy that
+    // sets the `X-Forwarded-Proto` and `X-Forwarded-Host` headers
+    fn resolve_app_url(&self, request: List) -> Result<String> {
+        let request = ForwardedRequest::try_from(request)?;
+        Ok(request
+            .public_app_url(&self.options.trusted_proxies)
+            .unwrap_or_else(|| self.app_url.clone()))
+    }
+
+    // Returns the URL to send the user to after a successful login: the
+    // path carried in `state` if it stays within the app, the app
+    // itself otherwise
+    fn get_return_url(&self, state: Option<String>, app_url: &str) -> String {
+        state
+            .and_then(|state| state::return_url(&state, app_url))
+            .unwrap_or_else(|| format!("{}/", app_url.trim_end_matches('/')))
+    }
+
+    // Returns the path carried in `state` if it stays within the app,
+    // to start a new login that goes back to the same place
+    fn get_return_path(&self, state: Option<String>, app_url: &str) -> Nullable<String> {
+        match state.and_then(|state| state::return_path(&state, app_url)) {
+            Some(path) => Nullable::NotNull(path),
+            None => Nullable::Null,
+        }
+    }
+
+    // Builds the values of the `Set-Cookie` headers storing a cookie
+    // with the attributes of this runtime. `max_age` is in seconds,
+    // `NULL` builds a session cookie. `cookie_string` is the `Cookie`
+    // header of the request, used to clear leftover chunks
+    fn build_cookie(
+        &self,
+        name: &str,
+        value: &str,
+        max_age: Option<f64>,
+        cookie_string: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let existing = self.cookies.names(cookie_string.unwrap_or(""));
+        Ok(self.cookies.build(
+            name,
+            value,
+            max_age.map(cookies::max_age_seconds),
+            &existing,
+        )?)
+    }
+
+    fn clear_cookie(&self, name: &str, cookie_string: Option<&str>) -> Result<Vec<String>> {
+        let existing = self.cookies.names(cookie_string.unwrap_or(""));
+        Ok(self.cookies.clear(name, &existing)?)
+    }
+
+    // Parses the `Cookie` header of a request, only keeping the cookies
+    // with the configured prefix. Sealed cookies that have been tampered
+    // with are dropped, their names are in the `tampered` attribute
+    fn parse_cookies(&self, cookie_string: Option<&str>) -> Result<Robj> {
+        let input = cookie_string.unwrap_or("").trim();
+        if input.is_empty() {
+            return Ok(List::new(0).into());
+        }
+        self.cookies.parse(input).into_list()
+    }
+
+    // Builds the cookie recording the login time when the session
+    // timeouts are tracked in cookies, nothing otherwise
+    fn start_session_cookie(&self, cookie_string: Option<&str>) -> Result<Vec<String>> {
+        if !self.tracks_session_cookie() {
+            return Ok(Vec::new());
+        }
+        Ok(self.session_cookie(SessionTimes::start(), cookie_string)?)
+    }
+
+    // Checks the session timeouts against the session cookie. Fails if
+    // the session has timed out (or the cookie is missing), otherwise
+    // returns the cookie to send back to record the activity, if any
+    fn check_session_cookie(
+        &self,
+        value: Option<&str>,
+        cookie_string: Option<&str>,
+    ) -> Result<Vec<String>> {
+        if !self.tracks_session_cookie() {
+            return Ok(Vec::new());
+        }
+        let (times, changed) = value
+            .and_then(SessionTimes::parse)
+            .and_then(|times| times.touch(&self.options.sessions.timeouts))
+            .ok_or(TapLockError::SessionExpired)?;
+        if !changed {
+            return Ok(Vec::new());
+        }
+        Ok(self.session_cookie(times, cookie_string)?)
+    }
+
+    fn uses_sessions(&self) -> bool {
+        self.sessions.is_some()
+    }
+
+    // Stores the tokens of a new login in the session store and returns
+    // the id of the session, to be sent in the session cookie
+    fn create_session(
+        &self,
+        access_token: String,
+        refresh_token: Option<String>,
+        subject: Option<String>,
+    ) -> Result<String> {
+        let session = self
+            .sessions()?
+            .create(subject, access_token, refresh_token)?;
+        Ok(session.id)
+    }
+
+    // Returns the tokens of a session, or `NULL` if the session does not
+    // exist or has timed out
+    fn get_session(&self, session_id: Option<&str>) -> Result<Nullable<List>> {
+        let Some(session_id) = session_id else {
+            return Ok(Null);
+        };
+        Ok(match self.sessions()?.get(session_id)? {
+            Some(session) => NotNull(list!(
+                id = session.id,
+                access_token = session.access_token,
+                refresh_token = session.refresh_token
+            )),
+            None => Null,
+        })
+    }
+
+    // Replaces the tokens of a session after a refresh. Returns `FALSE`
+    // if the session no longer exists
+    fn update_session(
+        &self,
+        session_id: &str,
+        access_token: String,
+        refresh_token: Option<String>,
+    ) -> Result<bool> {
+        let session = self
+            .sessions()?
+            .update(session_id, access_token, refresh_token)?;
+        Ok(session.is_some())
+    }
+
+    fn invalidate_session(&self, session_id: &str) -> Result<()> {
+        Ok(self.sessions()?.invalidate(session_id)?)
+    }
+
+    // The active sessions of a user (the `sub` claim), without tokens
+    fn list_sessions(&self, subject: &str) -> Result<List> {
+        let sessions = self.sessions()?.list(subject)?;
+        Ok(List::from_values(sessions.iter().map(session_info)))
+    }
+
+    fn get_callback_path(&self) -> String {
+        self.options.callback_path.clone()
+    }
+
+    fn get_logout_path(&self) -> String {
+        self.options.logout_path.clone()
+    }
 }
 
 #[extendr]
@@ -187,7 +618,11 @@ fn initialize_google_runtime(
     client_secret: &str,
     app_url: &str,
     use_refresh_token: bool,
+    hosted_domains: Vec<String>,
+    options: List,
 ) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(1)
         .enable_all()
@@ -199,17 +634,16 @@ fn initialize_google_runtime(
         client_secret,
         app_url,
         use_refresh_token,
+        hosted_domains,
+        &options,
     ))?;
 
-    let client = Arc::from(client);
-
-    let app_url = Strings::from(app_url).into_robj();
-
-    Ok(OAuth2Runtime {
-        client,
+    Ok(OAuth2Runtime::new(
         runtime,
+        Arc::new(client),
         app_url,
-    })
+        options,
+    )?)
 }
 
 #[extendr]
@@ -219,7 +653,10 @@ fn initialize_entra_id_runtime(
     app_url: &str,
     tenant_id: &str,
     use_refresh_token: bool,
+    options: List,
 ) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(1)
         .enable_all()
@@ -232,17 +669,15 @@ fn initialize_entra_id_runtime(
         app_url,
         use_refresh_token,
         tenant_id,
+        &options,
     ))?;
 
-    let client = Arc::from(client);
-
-    let app_url = Strings::from(app_url).into_robj();
-
-    Ok(OAuth2Runtime {
-        client,
+    Ok(OAuth2Runtime::new(
         runtime,
+        Arc::new(client),
         app_url,
-    })
+        options,
+    )?)
 }
 
 #[extendr]
@@ -253,7 +688,10 @@ fn initialize_keycloak_runtime(
     base_url: &str,
     realm: &str,
     use_refresh_token: bool,
+    options: List,
 ) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(1)
         .enable_all()
@@ -267,17 +705,262 @@ fn initialize_keycloak_runtime(
         base_url,
         realm,
         use_refresh_token,
+        &options,
+    ))?;
+
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
+
+#[extendr]
+fn initialize_okta_runtime(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    domain: &str,
+    #[default = "NULL"] authorization_server: Option<&str>,
+    use_refresh_token: bool,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(okta::build_oauth2_state_okta(
+        client_id,
+        client_secret,
+        app_url,
+        domain,
+        authorization_server,
+        use_refresh_token,
+        &options,
+    ))?;
+
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
+
+#[extendr]
+fn initialize_auth0_runtime(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    domain: &str,
+    #[default = "NULL"] audience: Option<&str>,
+    use_refresh_token: bool,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(auth0::build_oauth2_state_auth0(
+        client_id,
+        client_secret,
+        app_url,
+        domain,
+        audience,
+        use_refresh_token,
+        &options,
+    ))?;
+
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
+
+#[extendr]
+fn initialize_cognito_runtime(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    domain: &str,
+    user_pool_id: &str,
+    use_refresh_token: bool,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(cognito::build_oauth2_state_cognito(
+        client_id,
+        client_secret,
+        app_url,
+        domain,
+        user_pool_id,
+        use_refresh_token,
+        &options,
+    ))?;
+
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
+
+#[extendr]
+fn initialize_gitlab_runtime(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    base_url: &str,
+    use_refresh_token: bool,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(gitlab::build_oauth2_state_gitlab(
+        client_id,
+        client_secret,
+        app_url,
+        base_url,
+        use_refresh_token,
+        &options,
+    ))?;
+
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
+
+#[extendr]
+fn initialize_apple_runtime(
+    client_id: &str,
+    team_id: &str,
+    key_id: &str,
+    private_key: &str,
+    app_url: &str,
+    use_refresh_token: bool,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(apple::build_oauth2_state_apple(
+        client_id,
+        team_id,
+        key_id,
+        private_key,
+        app_url,
+        use_refresh_token,
+        &options,
     ))?;
 
-    let client = Arc::from(client);
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
 
-    let app_url = Strings::from(app_url).into_robj();
+#[extendr]
+fn initialize_github_runtime(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    base_url: &str,
+    api_url: &str,
+    organizations: Vec<String>,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(github::build_oauth2_state_github(
+        client_id,
+        client_secret,
+        app_url,
+        base_url,
+        api_url,
+        organizations,
+        &options,
+    ))?;
+
+    Ok(OAuth2Runtime::new(
+        runtime,
+        Arc::new(client),
+        app_url,
+        options,
+    )?)
+}
+
+#[extendr]
+fn initialize_oauth2_runtime(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    provider: List,
+    options: List,
+) -> Result<OAuth2Runtime> {
+    let provider = GenericProviderOptions::try_from(provider)?;
+    let options = RuntimeOptions::try_from(options)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(TapLockError::Io)?;
+
+    let client = runtime.block_on(generic::build_oauth2_state_generic(
+        client_id,
+        client_secret,
+        app_url,
+        provider,
+        &options,
+    ))?;
 
-    Ok(OAuth2Runtime {
-        client,
+    Ok(OAuth2Runtime::new(
         runtime,
+        Arc::new(client),
         app_url,
-    })
+        options,
+    )?)
 }
 
 /// Return string `"Hello world!"` to R.
@@ -292,11 +975,21 @@ fn hello_world() -> &'static str {
 // See corresponding C code in `entrypoint.c`.
 extendr_module! {
     mod tapLock;
+    use callback;
     use cookies;
+    use policy;
+    use seal;
     fn hello_world;
     fn initialize_google_runtime;
     fn initialize_entra_id_runtime;
     fn initialize_keycloak_runtime;
+    fn initialize_okta_runtime;
+    fn initialize_auth0_runtime;
+    fn initialize_cognito_runtime;
+    fn initialize_gitlab_runtime;
+    fn initialize_apple_runtime;
+    fn initialize_github_runtime;
+    fn initialize_oauth2_runtime;
     impl AsyncFuture;
     impl FutureResult;
     impl OAuth2Runtime;
@@ -0,0 +1,359 @@
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{decode, decode_header, DecodingKey};
+use oauth2::TokenResponse;
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    AuthType, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, RedirectUrl, Scope,
+    StandardRevocableToken, StandardTokenResponse, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use crate::error::TapLockError;
+use crate::jwks::JwksClient;
+use crate::options::{AuthorizationUrlOptions, RuntimeOptions};
+use crate::retry::RetryPolicy;
+use crate::validation::TokenValidation;
+use crate::{OAuth2Client, OAuth2Response};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct OidcTokenResponseExtra {
+    id_token: String,
+}
+
+impl oauth2::ExtraTokenFields for OidcTokenResponseExtra {}
+
+type OidcClientFull = Client<
+    BasicErrorResponse,
+    StandardTokenResponse<OidcTokenResponseExtra, BasicTokenType>,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    oauth2::EndpointSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointNotSet,
+    oauth2::EndpointSet,
+>;
+
+/// Maps the claims of a provider to the ones policies expect.
+pub(crate) type NormalizeClaims = Arc<dyn Fn(&mut serde_json::Value) + Send + Sync>;
+
+/// Signs a new client secret, for providers that do not issue a fixed
+/// one.
+pub(crate) type SignClientSecret =
+    Arc<dyn Fn() -> Result<ClientSecret, TapLockError> + Send + Sync>;
+
+/// What sets OpenID Connect providers with fixed endpoints (Okta,
+/// Auth0, Cognito, GitLab and Apple) apart from each other.
+#[derive(Clone)]
+pub(crate) struct OidcProvider {
+    /// The `iss` claim of the tokens
+    pub issuer: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub jwks_url: String,
+    /// The scopes requested unless the `scopes` option is set
+    pub default_scopes: &'static [&'static str],
+    /// Authorization parameters that always replace those of the
+    /// `auth_params` and `response_mode` options, and the per-request
+    /// ones, with the same name
+    pub auth_params: Vec<(String, String)>,
+    /// Maps the claims of the provider to the ones policies expect
+    pub normalize_claims: NormalizeClaims,
+    /// Replaces the client secret of the app in every token request,
+    /// where it is sent in the request body
+    pub sign_client_secret: Option<SignClientSecret>,
+}
+
+/// The URL of a provider given by its domain, HTTPS unless a scheme is
+/// given.
+pub(crate) fn domain_url(domain: &str) -> String {
+    let domain = domain.trim_end_matches('/');
+    if domain.contains("://") {
+        domain.to_string()
+    } else {
+        format!("https://{domain}")
+    }
+}
+
+#[derive(Clone)]
+pub struct OidcOAuth2Client {
+    reqwest_client: reqwest::Client,
+    client: OidcClientFull,
+    client_id: String,
+    issuer: String,
+    jwks_client: JwksClient,
+    use_refresh_token: bool,
+    retry: RetryPolicy,
+    validation: TokenValidation,
+    scopes: Vec<Scope>,
+    auth_params: Vec<(String, String)>,
+    forced_auth_params: Vec<(String, String)>,
+    normalize_claims: NormalizeClaims,
+    sign_client_secret: Option<SignClientSecret>,
+}
+
+impl OidcOAuth2Client {
+    // The client for requests to the token endpoint
+    fn token_client(&self) -> Result<Cow<'_, OidcClientFull>, TapLockError> {
+        match &self.sign_client_secret {
+            Some(sign) => Ok(Cow::Owned(self.client.clone().set_client_secret(sign()?))),
+            None => Ok(Cow::Borrowed(&self.client)),
+        }
+    }
+}
+
+fn decode_with_key(
+    client: &OidcOAuth2Client,
+    access_token: String,
+    algo: jsonwebtoken::Algorithm,
+    decoding_key: &Jwk,
+) -> Result<OAuth2Response, TapLockError> {
+    let token_trim = access_token.trim_start_matches("Bearer").trim();
+    let mut validation = client.validation.jwt_validation(algo, &client.client_id);
+    validation.set_issuer(&[&client.issuer]);
+    let val = decode::<serde_json::Value>(
+        token_trim,
+        &DecodingKey::from_jwk(decoding_key)?,
+        &validation,
+    )?;
+    client
+        .validation
+        .check_claims(&val.claims, &client.client_id)?;
+    let mut fields = val.claims;
+    (client.normalize_claims)(&mut fields);
+
+    Ok(OAuth2Response {
+        access_token,
+        refresh_token: None,
+        fields,
+        warnings: Vec::new(),
+    })
+}
+
+fn decode_access_token(
+    client: &OidcOAuth2Client,
+    access_token: String,
+) -> Result<OAuth2Response, TapLockError> {
+    let token_trim = access_token.trim_start_matches("Bearer").trim();
+    let jwt_header = decode_header(token_trim)?;
+    let kid = jwt_header.kid.ok_or(TapLockError::KidNotFound)?;
+    let decoding_key = client
+        .jwks_client
+        .get_key(&kid)
+        .ok_or(TapLockError::KidNotFound)?;
+    decode_with_key(client, access_token, jwt_header.alg, &decoding_key)
+}
+
+async fn decode_token_and_maybe_refresh_jwks(
+    client: &OidcOAuth2Client,
+    access_token: String,
+) -> Result<OAuth2Response, TapLockError> {
+    let token_trim = access_token.trim_start_matches("Bearer").trim();
+    let jwt_header = decode_header(token_trim)?;
+    let kid = jwt_header.kid.ok_or(TapLockError::KidNotFound)?;
+    let decoding_key = client.jwks_client.get_key_with_refresh(&kid).await?;
+    decode_with_key(client, access_token, jwt_header.alg, &decoding_key)
+}
+
+// `auth_params` with the parameters the provider forces on top
+fn force_auth_params(
+    mut auth_params: Vec<(String, String)>,
+    forced: &[(String, String)],
+) -> Vec<(String, String)> {
+    auth_params.retain(|(name, _)| !forced.iter().any(|(forced, _)| forced == name));
+    auth_params.extend(forced.iter().cloned());
+    auth_params
+}
+
+pub async fn build_oauth2_state_oidc(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    provider: OidcProvider,
+    use_refresh_token: bool,
+    options: &RuntimeOptions,
+) -> std::result::Result<OidcOAuth2Client, TapLockError> {
+    let mut client = Client::new(ClientId::new(client_id.to_string()))
+        .set_client_secret(ClientSecret::new(client_secret.to_string()))
+        .set_auth_uri(AuthUrl::new(provider.auth_url.clone())?)
+        .set_token_uri(TokenUrl::new(provider.token_url.clone())?)
+        .set_redirect_uri(options.redirect_url(app_url)?);
+    if let Some(sign) = &provider.sign_client_secret {
+        // Fails early on a key that cannot sign
+        sign()?;
+        client = client.set_auth_type(AuthType::RequestBody);
+    }
+
+    let reqwest_client = reqwest::Client::new();
+
+    let jwks_client = JwksClient::new(
+        provider.jwks_url.clone(),
+        reqwest_client.clone(),
+        options.retry,
+    )
+    .await?;
+
+    Ok(OidcOAuth2Client {
+        reqwest_client,
+        client,
+        jwks_client,
+        client_id: client_id.to_string(),
+        use_refresh_token,
+        retry: options.retry,
+        validation: options.validation.clone(),
+        scopes: options.scopes_or(provider.default_scopes),
+        auth_params: force_auth_params(options.auth_params_or(&[]), &provider.auth_params),
+        forced_auth_params: provider.auth_params,
+        normalize_claims: provider.normalize_claims,
+        sign_client_secret: provider.sign_client_secret,
+        issuer: provider.issuer,
+    })
+}
+
+#[async_trait::async_trait]
+impl OAuth2Client for OidcOAuth2Client {
+    async fn exchange_refresh_token(
+        &self,
+        refresh_token: String,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        if !self.use_refresh_token {
+            return Err(TapLockError::new("Refresh token is disabled"));
+        }
+        let client = self.token_client()?;
+        let token_result = self
+            .retry
+            .token_request(|| async {
+                Ok(client
+                    .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+                    .add_scopes(self.scopes.iter().cloned())
+                    .request_async(&self.reqwest_client)
+                    .await?)
+            })
+            .await?;
+
+        let access_token = token_result.extra_fields().id_token.clone();
+        let mut response = decode_token_and_maybe_refresh_jwks(self, access_token).await?;
+        if self.use_refresh_token {
+            response.refresh_token = Some(
+                token_result
+                    .refresh_token()
+                    .map(|rt| rt.secret().clone())
+                    .unwrap_or(refresh_token),
+            );
+        }
+        Ok(response)
+    }
+    async fn exchange_code(
+        &self,
+        code: String,
+        redirect_url: Option<RedirectUrl>,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let client = self.token_client()?;
+        let token_result = self
+            .retry
+            .token_request(|| async {
+                let mut request = client.exchange_code(AuthorizationCode::new(code.clone()));
+                if let Some(redirect_url) = &redirect_url {
+                    request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+                }
+                Ok(request.request_async(&self.reqwest_client).await?)
+            })
+            .await?;
+
+        let access_token = token_result.extra_fields().id_token.clone();
+        let mut response = decode_token_and_maybe_refresh_jwks(self, access_token).await?;
+
+        if self.use_refresh_token {
+            response.refresh_token = token_result.refresh_token().map(|rt| rt.secret().clone());
+        }
+
+        Ok(response)
+    }
+    fn decode_access_token(
+        &self,
+        access_token: String,
+    ) -> std::result::Result<OAuth2Response, TapLockError> {
+        let response = decode_access_token(self, access_token)?;
+        Ok(response)
+    }
+    fn get_authorization_url(&self, options: &AuthorizationUrlOptions) -> String {
+        let mut request = self
+            .client
+            .authorize_url(|| options.csrf_token())
+            .add_scopes(self.scopes.iter().cloned());
+        if let Some(redirect_url) = &options.redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        let auth_params = options.merge_into(&self.auth_params);
+        for (name, value) in force_auth_params(auth_params, &self.forced_auth_params) {
+            request = request.add_extra_param(name, value);
+        }
+        let (auth_url, _csrf_token) = request.url();
+        auth_url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn provider(auth_params: &[(&str, &str)]) -> OidcProvider {
+        OidcProvider {
+            issuer: "https://id.example.com".to_string(),
+            auth_url: "https://id.example.com/authorize".to_string(),
+            token_url: "https://id.example.com/token".to_string(),
+            jwks_url: "https://id.example.com/keys".to_string(),
+            default_scopes: &["openid"],
+            auth_params: pairs(auth_params),
+            normalize_claims: Arc::new(|_| {}),
+            sign_client_secret: None,
+        }
+    }
+
+    #[test]
+    fn domains_default_to_https() {
+        assert_eq!(domain_url("id.example.com/"), "https://id.example.com");
+        assert_eq!(domain_url("http://localhost:8080"), "http://localhost:8080");
+    }
+
+    #[test]
+    fn forced_auth_params_replace_the_option() {
+        assert_eq!(
+            force_auth_params(
+                pairs(&[("audience", "other"), ("prompt", "login")]),
+                &provider(&[("audience", "api")]).auth_params
+            ),
+            pairs(&[("prompt", "login"), ("audience", "api")])
+        );
+        assert!(force_auth_params(Vec::new(), &[]).is_empty());
+    }
+
+    #[test]
+    fn forced_auth_params_replace_the_per_request_ones() {
+        // As the runtime passes the `response_mode` option
+        let options = AuthorizationUrlOptions {
+            response_mode: Some("query".to_string()),
+            prompt: Some("login".to_string()),
+            ..AuthorizationUrlOptions::default()
+        };
+        let forced = pairs(&[("response_mode", "form_post")]);
+        let auth_params = force_auth_params(Vec::new(), &forced);
+        assert_eq!(
+            force_auth_params(options.merge_into(&auth_params), &forced),
+            pairs(&[("prompt", "login"), ("response_mode", "form_post")])
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use crate::error::TapLockError;
+use crate::oidc::{self, OidcOAuth2Client, OidcProvider};
+use crate::options::RuntimeOptions;
+
+// Okta only adds the `groups` claim when the app (or the authorization
+// server) has a groups claim configured, so it is always set to keep
+// policies on it from failing on a missing claim
+fn normalize_groups(claims: &mut serde_json::Value) {
+    if let Some(claims) = claims.as_object_mut() {
+        claims
+            .entry("groups")
+            .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+    }
+}
+
+fn provider(domain: &str, authorization_server: Option<&str>) -> OidcProvider {
+    let base_url = oidc::domain_url(domain);
+    // The org authorization server lives at the root of the Okta
+    // domain, custom ones (such as `default`) under `/oauth2/<id>`
+    let (issuer, endpoints) = match authorization_server {
+        Some(server) if !server.is_empty() => {
+            let issuer = format!("{base_url}/oauth2/{server}");
+            (issuer.clone(), issuer)
+        }
+        _ => (base_url.clone(), format!("{base_url}/oauth2")),
+    };
+    OidcProvider {
+        auth_url: format!("{endpoints}/v1/authorize"),
+        token_url: format!("{endpoints}/v1/token"),
+        jwks_url: format!("{endpoints}/v1/keys"),
+        issuer,
+        default_scopes: &["openid", "email", "profile", "offline_access"],
+        auth_params: Vec::new(),
+        normalize_claims: Arc::new(normalize_groups),
+        sign_client_secret: None,
+    }
+}
+
+pub async fn build_oauth2_state_okta(
+    client_id: &str,
+    client_secret: &str,
+    app_url: &str,
+    domain: &str,
+    authorization_server: Option<&str>,
+    use_refresh_token: bool,
+    options: &RuntimeOptions,
+) -> std::result::Result<OidcOAuth2Client, TapLockError> {
+    oidc::build_oauth2_state_oidc(
+        client_id,
+        client_secret,
+        app_url,
+        provider(domain, authorization_server),
+        use_refresh_token,
+        options,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn uses_the_org_authorization_server_by_default() {
+        let org = provider("dev-123.okta.com/", None);
+        assert_eq!(org.issuer, "https://dev-123.okta.com");
+        assert_eq!(org.auth_url, "https://dev-123.okta.com/oauth2/v1/authorize");
+        assert_eq!(org.token_url, "https://dev-123.okta.com/oauth2/v1/token");
+        assert_eq!(org.jwks_url, "https://dev-123.okta.com/oauth2/v1/keys");
+        assert_eq!(provider("dev-123.okta.com", Some("")).issuer, org.issuer);
+    }
+
+    #[test]
+    fn uses_custom_authorization_servers() {
+        let provider = provider("https://dev-123.okta.com", Some("default"));
+        assert_eq!(provider.issuer, "https://dev-123.okta.com/oauth2/default");
+        assert_eq!(
+            provider.auth_url,
+            "https://dev-123.okta.com/oauth2/default/v1/authorize"
+        );
+        assert_eq!(
+            provider.jwks_url,
+            "https://dev-123.okta.com/oauth2/default/v1/keys"
+        );
+    }
+
+    #[test]
+    fn always_sets_groups() {
+        let mut claims = json!({ "sub": "user" });
+        normalize_groups(&mut claims);
+        assert_eq!(claims["groups"], json!([]));
+
+        let mut claims = json!({ "groups": ["admins"] });
+        normalize_groups(&mut claims);
+        assert_eq!(claims["groups"], json!(["admins"]));
+    }
+}
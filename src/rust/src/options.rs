@@ -0,0 +1,486 @@
+use extendr_api::prelude::*;
+use oauth2::{CsrfToken, RedirectUrl, Scope};
+use std::time::Duration;
+
+use crate::cookies::{CookieOptions, CookiePrefix, SameSite};
+use crate::error::TapLockError;
+use crate::graph::GRAPH_URL;
+use crate::policy::{Policy, PolicyRules};
+use crate::proxy::{ForwardedRequest, IpRange};
+use crate::retry::RetryPolicy;
+use crate::seal::KeyRing;
+use crate::session::SessionOptions;
+use crate::state;
+use crate::validation::{Clock, TokenValidation};
+
+/// Settings shared by every provider, built from the named list
+/// returned by `runtime_options()` on the R side.
+#[derive(Debug, Clone)]
+pub(crate) struct RuntimeOptions {
+    pub retry: RetryPolicy,
+    pub scopes: Option<Vec<String>>,
+    pub auth_params: Option<Vec<(String, String)>>,
+    pub callback_path: String,
+    pub logout_path: String,
+    pub trusted_proxies: Vec<IpRange>,
+    pub cookies: CookieOptions,
+    pub sessions: SessionOptions,
+    /// Number of verified tokens to remember, 0 disables the cache
+    pub token_cache_size: usize,
+    /// Tokens expiring within this window are reported as `expiring`
+    pub refresh_ahead: Duration,
+    pub validation: TokenValidation,
+    pub policies: PolicyRules,
+    /// Add the roles of every Keycloak client as `<client>:<role>`
+    pub prefix_client_roles: bool,
+    /// Microsoft Graph, queried for the groups of Entra ID users
+    pub graph_url: String,
+    /// The namespace of the custom claims of Auth0 tokens, copied
+    /// without it
+    pub claims_namespace: Option<String>,
+    /// How long the claims of opaque tokens are trusted
+    pub userinfo_ttl: Duration,
+    /// How the provider returns to the callback, `query` or `form_post`,
+    /// the provider's default when `None`
+    pub response_mode: Option<String>,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        Self {
+            retry: RetryPolicy::default(),
+            scopes: None,
+            auth_params: None,
+            callback_path: "/login".to_string(),
+            logout_path: "/logout".to_string(),
+            trusted_proxies: Vec::new(),
+            cookies: CookieOptions::default(),
+            sessions: SessionOptions::default(),
+            token_cache_size: 1000,
+            refresh_ahead: Duration::from_secs(60),
+            validation: TokenValidation::default(),
+            policies: PolicyRules::default(),
+            prefix_client_roles: false,
+            graph_url: GRAPH_URL.to_string(),
+            claims_namespace: None,
+            userinfo_ttl: Duration::from_secs(300),
+            response_mode: None,
+        }
+    }
+}
+
+impl RuntimeOptions {
+    /// The scopes to request, falling back to the provider's defaults.
+    /// `openid` is always included since every provider relies on the
+    /// ID token.
+    pub fn scopes_or(&self, defaults: &[&str]) -> Vec<Scope> {
+        let mut scopes = self.oauth2_scopes_or(defaults);
+        if !scopes.iter().any(|s| s.as_str() == "openid") {
+            scopes.insert(0, Scope::new("openid".to_string()));
+        }
+        scopes
+    }
+
+    /// The scopes to request from providers without OpenID Connect,
+    /// falling back to the provider's defaults.
+    pub fn oauth2_scopes_or(&self, defaults: &[&str]) -> Vec<Scope> {
+        let scopes = match &self.scopes {
+            Some(scopes) => scopes.clone(),
+            None => defaults.iter().map(|s| s.to_string()).collect(),
+        };
+        scopes.into_iter().map(Scope::new).collect()
+    }
+
+    /// The URL the provider redirects to after login for a given app URL.
+    pub fn redirect_url(&self, app_url: &str) -> std::result::Result<RedirectUrl, TapLockError> {
+        let app_url = app_url.trim_end_matches('/');
+        Ok(RedirectUrl::new(format!(
+            "{app_url}{}",
+            self.callback_path
+        ))?)
+    }
+
+    /// The extra authorization URL parameters, falling back to the
+    /// provider's defaults.
+    pub fn auth_params_or(&self, defaults: &[(&str, &str)]) -> Vec<(String, String)> {
+        match &self.auth_params {
+            Some(params) => params.clone(),
+            None => defaults
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// Per-request parameters for the authorization URL. They take
+/// precedence over the runtime's `auth_params`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuthorizationUrlOptions {
+    pub login_hint: Option<String>,
+    pub domain_hint: Option<String>,
+    pub hd: Option<String>,
+    pub prompt: Option<String>,
+    pub max_age: Option<u64>,
+    pub ui_locales: Option<String>,
+    /// Path (and query string) of the app to come back to after login.
+    pub return_to: Option<String>,
+    /// Set by the runtime when the app is reached through a trusted proxy.
+    pub redirect_url: Option<RedirectUrl>,
+    /// Set by the runtime from `RuntimeOptions::response_mode`.
+    pub response_mode: Option<String>,
+    /// Set by the runtime: the `state` binding the login to the browser
+    /// (see `state::new_state`).
+    pub state: Option<String>,
+}
+
+impl AuthorizationUrlOptions {
+    /// The `state` of the authorization URL. Without one set by the
+    /// runtime, the state is not bound to any browser.
+    pub fn csrf_token(&self) -> CsrfToken {
+        CsrfToken::new(
+            self.state.clone().unwrap_or_else(|| {
+                state::new_state(&state::new_nonce(), self.return_to.as_deref())
+            }),
+        )
+    }
+
+    /// Merges these options on top of the runtime's extra parameters.
+    pub fn merge_into(&self, base: &[(String, String)]) -> Vec<(String, String)> {
+        let overrides = [
+            ("login_hint", self.login_hint.clone()),
+            ("domain_hint", self.domain_hint.clone()),
+            ("hd", self.hd.clone()),
+            ("prompt", self.prompt.clone()),
+            ("max_age", self.max_age.map(|v| v.to_string())),
+            ("ui_locales", self.ui_locales.clone()),
+            ("response_mode", self.response_mode.clone()),
+        ];
+        let mut params: Vec<(String, String)> = base
+            .iter()
+            .filter(|(name, _)| {
+                !overrides
+                    .iter()
+                    .any(|(key, value)| value.is_some() && name == key)
+            })
+            .cloned()
+            .collect();
+        params.extend(
+            overrides
+                .into_iter()
+                .filter_map(|(key, value)| Some((key.to_string(), value?))),
+        );
+        params
+    }
+}
+
+/// The endpoints and claim mapping of a generic OAuth2 provider, built
+/// from the named list passed by `new_oauth2_config()`.
+#[derive(Debug, Clone)]
+pub(crate) struct GenericProviderOptions {
+    pub auth_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    /// `(claim, path)` pairs, where `path` is a dot separated path in
+    /// the userinfo response
+    pub claims: Vec<(String, String)>,
+    /// Send the client secret in the request body instead of with
+    /// HTTP Basic authentication
+    pub secret_in_body: bool,
+    pub use_refresh_token: bool,
+}
+
+impl TryFrom<List> for GenericProviderOptions {
+    type Error = TapLockError;
+
+    fn try_from(list: List) -> std::result::Result<Self, Self::Error> {
+        let mut auth_url = None;
+        let mut token_url = None;
+        let mut userinfo_url = None;
+        let mut options = GenericProviderOptions {
+            auth_url: String::new(),
+            token_url: String::new(),
+            userinfo_url: String::new(),
+            claims: ["sub", "email", "name"]
+                .map(|claim| (claim.to_string(), claim.to_string()))
+                .to_vec(),
+            secret_in_body: false,
+            use_refresh_token: true,
+        };
+        for (name, value) in list.iter() {
+            if value.is_null() {
+                continue;
+            }
+            match name {
+                "auth_url" => auth_url = Some(as_string(name, &value)?),
+                "token_url" => token_url = Some(as_string(name, &value)?),
+                "userinfo_url" => userinfo_url = Some(as_string(name, &value)?),
+                // Mapped claims replace the default mapping of the same claim
+                "claims" => {
+                    for (claim, path) in as_string_pairs(name, &value)? {
+                        options.claims.retain(|(name, _)| *name != claim);
+                        options.claims.push((claim, path));
+                    }
+                }
+                "client_auth" => {
+                    options.secret_in_body = match as_string(name, &value)?.as_str() {
+                        "basic" => false,
+                        "body" => true,
+                        _ => {
+                            return Err(TapLockError::new(
+                                "`client_auth` must be \"basic\" or \"body\"",
+                            ))
+                        }
+                    }
+                }
+                "use_refresh_token" => options.use_refresh_token = as_bool(name, &value)?,
+                _ => {
+                    return Err(TapLockError::new(format!(
+                        "Unknown provider option `{name}`"
+                    )))
+                }
+            }
+        }
+        let required = |name: &str, value: Option<String>| {
+            value.ok_or_else(|| TapLockError::new(format!("`{name}` is required")))
+        };
+        options.auth_url = required("auth_url", auth_url)?;
+        options.token_url = required("token_url", token_url)?;
+        options.userinfo_url = required("userinfo_url", userinfo_url)?;
+        Ok(options)
+    }
+}
+
+fn as_f64(name: &str, value: &Robj) -> std::result::Result<f64, TapLockError> {
+    value
+        .as_real()
+        .or_else(|| value.as_integer().map(f64::from))
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .ok_or_else(|| TapLockError::new(format!("`{name}` must be a non-negative number")))
+}
+
+fn as_bool(name: &str, value: &Robj) -> std::result::Result<bool, TapLockError> {
+    value
+        .as_bool()
+        .ok_or_else(|| TapLockError::new(format!("`{name}` must be `TRUE` or `FALSE`")))
+}
+
+fn as_string(name: &str, value: &Robj) -> std::result::Result<String, TapLockError> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| TapLockError::new(format!("`{name}` must be a string")))
+}
+
+fn as_strings(name: &str, value: &Robj) -> std::result::Result<Vec<String>, TapLockError> {
+    value
+        .as_string_vector()
+        .ok_or_else(|| TapLockError::new(format!("`{name}` must be a character vector")))
+}
+
+fn as_string_pairs(
+    name: &str,
+    value: &Robj,
+) -> std::result::Result<Vec<(String, String)>, TapLockError> {
+    let list = value
+        .as_list()
+        .ok_or_else(|| TapLockError::new(format!("`{name}` must be a named list")))?;
+    list.iter()
+        .map(|(key, value)| match value.as_str() {
+            Some(value) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(TapLockError::new(format!(
+                "`{name}` must be a named list of strings"
+            ))),
+        })
+        .collect()
+}
+
+// Either a character vector for the whole app or a named list of
+// character vectors per path
+fn as_path_strings(
+    name: &str,
+    value: &Robj,
+) -> std::result::Result<Vec<(String, Vec<String>)>, TapLockError> {
+    if let Some(strings) = value.as_string_vector() {
+        return Ok(vec![("/".to_string(), strings)]);
+    }
+    let list = value.as_list().ok_or_else(|| {
+        TapLockError::new(format!(
+            "`{name}` must be a character vector or a named list"
+        ))
+    })?;
+    list.iter()
+        .map(|(key, value)| match value.as_string_vector() {
+            Some(strings) if !key.is_empty() => Ok((key.to_string(), strings)),
+            _ => Err(TapLockError::new(format!(
+                "`{name}` must be a named list of character vectors"
+            ))),
+        })
+        .collect()
+}
+
+fn as_path(name: &str, value: &Robj) -> std::result::Result<String, TapLockError> {
+    let path = as_string(name, value)?;
+    let trimmed = path.trim_end_matches('/');
+    if !trimmed.starts_with('/') || trimmed.starts_with("//") {
+        return Err(TapLockError::new(format!(
+            "`{name}` must be a path such as `/login`"
+        )));
+    }
+    Ok(trimmed.to_string())
+}
+
+fn as_seconds(name: &str, value: &Robj) -> std::result::Result<Duration, TapLockError> {
+    Duration::try_from_secs_f64(as_f64(name, value)?)
+        .map_err(|_| TapLockError::new(format!("`{name}` is too large")))
+}
+
+impl TryFrom<List> for RuntimeOptions {
+    type Error = TapLockError;
+
+    fn try_from(list: List) -> std::result::Result<Self, Self::Error> {
+        let mut options = RuntimeOptions::default();
+        for (name, value) in list.iter() {
+            if value.is_null() {
+                continue;
+            }
+            match name {
+                "retry_max_attempts" => {
+                    options.retry.max_attempts = (as_f64(name, &value)? as u32).max(1)
+                }
+                "retry_initial_backoff" => {
+                    options.retry.initial_backoff = as_seconds(name, &value)?
+                }
+                "retry_max_backoff" => options.retry.max_backoff = as_seconds(name, &value)?,
+                "retry_deadline" => options.retry.deadline = as_seconds(name, &value)?,
+                "scopes" => options.scopes = Some(as_strings(name, &value)?),
+                "auth_params" => options.auth_params = Some(as_string_pairs(name, &value)?),
+                "callback_path" => options.callback_path = as_path(name, &value)?,
+                "logout_path" => options.logout_path = as_path(name, &value)?,
+                "trusted_proxies" => {
+                    options.trusted_proxies = as_strings(name, &value)?
+                        .iter()
+                        .map(|proxy| IpRange::parse(proxy))
+                        .collect::<std::result::Result<_, _>>()?
+                }
+                "cookie_secure" => options.cookies.secure = Some(as_bool(name, &value)?),
+                "cookie_prefix" => {
+                    options.cookies.prefix = CookiePrefix::parse(&as_string(name, &value)?)?
+                }
+                "cookie_domain" => options.cookies.domain = Some(as_string(name, &value)?),
+                "cookie_path" => options.cookies.path = as_string(name, &value)?,
+                "cookie_same_site" => {
+                    options.cookies.same_site = SameSite::parse(&as_string(name, &value)?)?
+                }
+                "cookie_keys" => {
+                    options.cookies.keys = Some(KeyRing::parse(&as_strings(name, &value)?)?)
+                }
+                "session_store" => options.sessions.store = Some(as_string(name, &value)?),
+                "session_dir" => options.sessions.dir = Some(as_string(name, &value)?.into()),
+                "session_idle_timeout" => {
+                    options.sessions.timeouts.idle = Some(as_seconds(name, &value)?)
+                }
+                "session_absolute_timeout" => {
+                    options.sessions.timeouts.absolute = Some(as_seconds(name, &value)?)
+                }
+                "refresh_ahead" => options.refresh_ahead = as_seconds(name, &value)?,
+                "leeway" => options.validation.leeway = as_f64(name, &value)? as u64,
+                "validate_nbf" => options.validation.validate_nbf = as_bool(name, &value)?,
+                "max_token_age" => options.validation.max_age = Some(as_seconds(name, &value)?),
+                "fixed_time" => {
+                    options.validation.clock = Clock::Fixed(as_f64(name, &value)? as u64)
+                }
+                "audiences" => options.validation.audiences = as_strings(name, &value)?,
+                "validate_azp" => options.validation.validate_azp = as_bool(name, &value)?,
+                // A single policy applies to the whole app
+                "policies" => {
+                    let rules = match value.as_str() {
+                        Some(policy) => vec![("/".to_string(), policy.to_string())],
+                        None => as_string_pairs(name, &value)?,
+                    };
+                    options.policies.extend(PolicyRules::parse(&rules)?)
+                }
+                "required_roles" => {
+                    for (path, roles) in as_path_strings(name, &value)? {
+                        if roles.is_empty() {
+                            continue;
+                        }
+                        options
+                            .policies
+                            .add(&path, Policy::require_roles(&roles)?)?;
+                    }
+                }
+                "prefix_client_roles" => options.prefix_client_roles = as_bool(name, &value)?,
+                "graph_url" => options.graph_url = as_string(name, &value)?,
+                "claims_namespace" => options.claims_namespace = Some(as_string(name, &value)?),
+                "userinfo_ttl" => options.userinfo_ttl = as_seconds(name, &value)?,
+                "response_mode" => {
+                    let mode = as_string(name, &value)?;
+                    if mode != "query" && mode != "form_post" {
+                        return Err(TapLockError::new(
+                            "`response_mode` must be \"query\" or \"form_post\"",
+                        ));
+                    }
+                    options.response_mode = Some(mode);
+                }
+                "token_cache_size" => options.token_cache_size = as_f64(name, &value)? as usize,
+                _ => {
+                    return Err(TapLockError::new(format!(
+                        "Unknown runtime option `{name}`"
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl TryFrom<List> for AuthorizationUrlOptions {
+    type Error = TapLockError;
+
+    fn try_from(list: List) -> std::result::Result<Self, Self::Error> {
+        let mut options = AuthorizationUrlOptions::default();
+        for (name, value) in list.iter() {
+            if value.is_null() {
+                continue;
+            }
+            match name {
+                "login_hint" => options.login_hint = Some(as_string(name, &value)?),
+                "domain_hint" => options.domain_hint = Some(as_string(name, &value)?),
+                "hd" => options.hd = Some(as_string(name, &value)?),
+                "prompt" => options.prompt = Some(as_strings(name, &value)?.join(" ")),
+                "max_age" => options.max_age = Some(as_f64(name, &value)? as u64),
+                "ui_locales" => options.ui_locales = Some(as_strings(name, &value)?.join(" ")),
+                "return_to" => options.return_to = Some(as_string(name, &value)?),
+                _ => {
+                    return Err(TapLockError::new(format!(
+                        "Unknown authorization URL option `{name}`"
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl TryFrom<List> for ForwardedRequest {
+    type Error = TapLockError;
+
+    fn try_from(list: List) -> std::result::Result<Self, Self::Error> {
+        let mut request = ForwardedRequest::default();
+        for (name, value) in list.iter() {
+            if value.is_null() {
+                continue;
+            }
+            let value = Some(as_string(name, &value)?);
+            match name {
+                "remote_addr" => request.remote_addr = value,
+                "forwarded_proto" => request.proto = value,
+                "forwarded_host" => request.host = value,
+                "forwarded_prefix" => request.prefix = value,
+                _ => (),
+            }
+        }
+        Ok(request)
+    }
+}
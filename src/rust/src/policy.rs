@@ -0,0 +1,550 @@
+use extendr_api::prelude::*;
+use serde_json::Value;
+
+use crate::error::TapLockError;
+use crate::options::RuntimeOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    In,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Claim(Vec<String>),
+    Literal(Value),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Token::Claim(claim) => return write!(f, "`{}`", claim.join(".")),
+            Token::Literal(value) => return write!(f, "`{value}`"),
+            Token::Op(op) => match op {
+                Op::Eq => "==",
+                Op::Ne => "!=",
+                Op::Lt => "<",
+                Op::Le => "<=",
+                Op::Gt => ">",
+                Op::Ge => ">=",
+                Op::Contains => "contains",
+                Op::In => "in",
+            },
+            Token::And => "and",
+            Token::Or => "or",
+            Token::Not => "not",
+            Token::Open => "(",
+            Token::Close => ")",
+            Token::OpenList => "[",
+            Token::CloseList => "]",
+            Token::Comma => ",",
+        };
+        write!(f, "`{text}`")
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Truthy(Vec<String>),
+    Compare(Vec<String>, Op, Value),
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':')
+}
+
+fn tokenize(source: &str) -> std::result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '[' | ']' | ',' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '[' => Token::OpenList,
+                    ']' => Token::CloseList,
+                    _ => Token::Comma,
+                });
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let equals = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Op(match (c, equals) {
+                    ('=', true) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => return Err(format!("unexpected `{c}`")),
+                }));
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => string.push(escaped),
+                            None => return Err("unterminated string".to_string()),
+                        },
+                        Some(c) => string.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(string)));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '.'))
+                {
+                    number.push(c);
+                }
+                let number = number
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .ok_or_else(|| format!("invalid number `{number}`"))?;
+                tokens.push(Token::Literal(Value::Number(number)));
+            }
+            c if is_name_char(c) || c == '`' => {
+                let mut segments = Vec::new();
+                loop {
+                    let mut segment = String::new();
+                    if chars.next_if_eq(&'`').is_some() {
+                        loop {
+                            match chars.next() {
+                                Some('`') => break,
+                                Some(c) => segment.push(c),
+                                None => return Err("unterminated `".to_string()),
+                            }
+                        }
+                    } else {
+                        while let Some(c) = chars.next_if(|c| is_name_char(*c)) {
+                            segment.push(c);
+                        }
+                    }
+                    if segment.is_empty() {
+                        return Err("empty claim name".to_string());
+                    }
+                    segments.push(segment);
+                    if chars.next_if_eq(&'.').is_none() {
+                        break;
+                    }
+                }
+                let keyword = match segments.as_slice() {
+                    [name] => match name.as_str() {
+                        "and" => Some(Token::And),
+                        "or" => Some(Token::Or),
+                        "not" => Some(Token::Not),
+                        "contains" => Some(Token::Op(Op::Contains)),
+                        "in" => Some(Token::Op(Op::In)),
+                        "true" => Some(Token::Literal(Value::Bool(true))),
+                        "false" => Some(Token::Literal(Value::Bool(false))),
+                        "null" => Some(Token::Literal(Value::Null)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                // Backticks always make a claim, so that claims named
+                // like keywords can be used
+                tokens.push(match keyword {
+                    Some(keyword) if c != '`' => keyword,
+                    _ => Token::Claim(segments),
+                });
+            }
+            c => return Err(format!("unexpected `{c}`")),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: std::vec::IntoIter<Token>,
+    peeked: Option<Token>,
+}
+
+impl Parser {
+    fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = self.tokens.next();
+        }
+        self.peeked.as_ref()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.peeked.take().or_else(|| self.tokens.next())
+    }
+
+    fn next_if(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.next();
+            return true;
+        }
+        false
+    }
+
+    fn expr(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.next_if(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.next_if(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> std::result::Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.expr()?;
+                if !self.next_if(&Token::Close) {
+                    return Err("expected `)`".to_string());
+                }
+                Ok(expr)
+            }
+            Some(Token::Claim(claim)) => match self.peek() {
+                Some(Token::Op(op)) => {
+                    let op = *op;
+                    self.next();
+                    let literal = self.literal()?;
+                    if op == Op::In && !literal.is_array() {
+                        return Err("`in` expects a list such as `[\"a\", \"b\"]`".to_string());
+                    }
+                    Ok(Expr::Compare(claim, op, literal))
+                }
+                _ => Ok(Expr::Truthy(claim)),
+            },
+            Some(token) => Err(format!("expected a claim, found {token}")),
+            None => Err("unexpected end of policy".to_string()),
+        }
+    }
+
+    fn literal(&mut self) -> std::result::Result<Value, String> {
+        match self.next() {
+            Some(Token::Literal(value)) => Ok(value),
+            Some(Token::OpenList) => {
+                let mut values = Vec::new();
+                if self.next_if(&Token::CloseList) {
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.literal()?);
+                    if self.next_if(&Token::CloseList) {
+                        return Ok(Value::Array(values));
+                    }
+                    if !self.next_if(&Token::Comma) {
+                        return Err("expected `,` or `]`".to_string());
+                    }
+                }
+            }
+            Some(token) => Err(format!("expected a value, found {token}")),
+            None => Err("unexpected end of policy".to_string()),
+        }
+    }
+}
+
+fn lookup<'a>(claims: &'a Value, claim: &[String]) -> Option<&'a Value> {
+    claim
+        .iter()
+        .try_fold(claims, |value, segment| value.get(segment.as_str()))
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+// Numbers are compared by value, so that `1` equals `1.0`
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(claim: &Value, op: Op, literal: &Value) -> bool {
+    match op {
+        Op::Eq => equals(claim, literal),
+        Op::Ne => !equals(claim, literal),
+        Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+            let (Some(a), Some(b)) = (claim.as_f64(), literal.as_f64()) else {
+                return false;
+            };
+            match op {
+                Op::Lt => a < b,
+                Op::Le => a <= b,
+                Op::Gt => a > b,
+                _ => a >= b,
+            }
+        }
+        Op::Contains => match (claim, literal) {
+            (Value::Array(items), _) => items.iter().any(|item| equals(item, literal)),
+            (Value::String(s), Value::String(sub)) => s.contains(sub.as_str()),
+            _ => false,
+        },
+        Op::In => {
+            let Value::Array(allowed) = literal else {
+                return false;
+            };
+            let is_allowed = |value: &Value| allowed.iter().any(|item| equals(item, value));
+            match claim {
+                Value::Array(items) => items.iter().any(is_allowed),
+                value => is_allowed(value),
+            }
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, claims: &Value) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(claims) || b.eval(claims),
+            Expr::And(a, b) => a.eval(claims) && b.eval(claims),
+            Expr::Not(expr) => !expr.eval(claims),
+            Expr::Truthy(claim) => lookup(claims, claim).is_some_and(is_truthy),
+            Expr::Compare(claim, op, literal) => {
+                lookup(claims, claim).is_some_and(|value| compare(value, *op, literal))
+            }
+        }
+    }
+}
+
+/// A small language for authorization rules on the claims of a token,
+/// for example `groups contains "analysts" and email_verified == true`.
+///
+/// ```text
+/// expr       := and ("or" and)*
+/// and        := unary ("and" unary)*
+/// unary      := "not" unary | "(" expr ")" | comparison
+/// comparison := claim (op literal)?
+/// op         := "==" | "!=" | "<" | "<=" | ">" | ">=" | "contains" | "in"
+/// literal    := string | number | "true" | "false" | "null" | "[" literal, ... "]"
+/// claim      := segment ("." segment)*
+/// ```
+///
+/// A segment is a name such as `email_verified`, or any text between
+/// backticks (`` `https://example.com/roles` ``). A claim on its own
+/// is true when it is present and not `false`, `null`, `0` or empty. A
+/// comparison with a missing claim is always false.
+#[derive(Debug, Clone)]
+pub(crate) struct Policy {
+    source: String,
+    expr: Expr,
+}
+
+impl Policy {
+    pub fn parse(source: &str) -> std::result::Result<Self, TapLockError> {
+        let invalid =
+            |message: String| TapLockError::new(format!("Invalid policy `{source}`: {message}"));
+        let mut parser = Parser {
+            tokens: tokenize(source).map_err(invalid)?.into_iter(),
+            peeked: None,
+        };
+        let expr = parser.expr().map_err(invalid)?;
+        if let Some(token) = parser.next() {
+            return Err(invalid(format!("unexpected {token}")));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    /// A policy requiring every one of `roles` in the `roles` claim.
+    pub fn require_roles(roles: &[String]) -> std::result::Result<Self, TapLockError> {
+        let source = roles
+            .iter()
+            .map(|role| {
+                let role = role.replace('\\', "\\\\").replace('"', "\\\"");
+                format!("roles contains \"{role}\"")
+            })
+            .collect::<Vec<_>>()
+            .join(" and ");
+        Self::parse(&source)
+    }
+
+    pub fn allows(&self, claims: &Value) -> bool {
+        self.expr.eval(claims)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+// Requests reach the same page through many spellings of its path, so
+// paths are percent-decoded, `.` and `..` are resolved and empty
+// segments dropped before they are matched. Backslashes are separators
+// too, as some servers and proxies treat them so.
+fn normalize_path(path: &str) -> String {
+    let decoded = urlencoding::decode_binary(path.as_bytes());
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut segments = Vec::new();
+    for segment in decoded.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// The policies of an app, each applying to the requests under a path.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PolicyRules {
+    // Path prefixes without trailing slash, "" for the whole app
+    rules: Vec<(String, Policy)>,
+}
+
+impl PolicyRules {
+    /// Compiles `(path, policy)` pairs. A policy applies to its path
+    /// and everything under it.
+    pub fn parse(rules: &[(String, String)]) -> std::result::Result<Self, TapLockError> {
+        let mut policies = Self::default();
+        for (path, policy) in rules {
+            policies.add(path, Policy::parse(policy)?)?;
+        }
+        Ok(policies)
+    }
+
+    pub fn add(&mut self, path: &str, policy: Policy) -> std::result::Result<(), TapLockError> {
+        if !path.starts_with('/') {
+            return Err(TapLockError::new(format!(
+                "Policy path `{path}` must start with `/`"
+            )));
+        }
+        self.rules.push((
+            normalize_path(path).trim_end_matches('/').to_string(),
+            policy,
+        ));
+        Ok(())
+    }
+
+    /// Adds the policies of `other` after ours.
+    pub fn extend(&mut self, other: PolicyRules) {
+        self.rules.extend(other.rules);
+    }
+
+    /// The first policy applying to `path` that `claims` fail, if any.
+    /// Every applying policy must pass, so that `/admin` can require
+    /// more than `/`. Paths match on whole segments once normalized, so
+    /// `/admin` applies to `/admin/users` and `//admin` but not to
+    /// `/administrator`.
+    pub fn check(&self, path: &str, claims: &Value) -> Option<&Policy> {
+        let path = normalize_path(path);
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map(|(_, policy)| policy)
+            .find(|policy| !policy.allows(claims))
+    }
+}
+
+/// @title Check the policies of a path
+/// @description Checks claims against the policies of `options` that
+///   apply to `path`, as the middleware does for every request
+///
+/// @param options A list of runtime options (see `runtime_options()`)
+/// @param path The path of the request
+/// @param claims The claims of the token, as JSON
+///
+/// @return The first policy the claims fail, `NULL` if they pass all
+///   of them
+/// @keywords internal
+#[extendr]
+fn check_policies(options: List, path: &str, claims: &str) -> Result<Nullable<String>> {
+    let options = RuntimeOptions::try_from(options)?;
+    let claims: Value = serde_json::from_str(claims)
+        .map_err(|err| TapLockError::new(format!("Invalid claims: {err}")))?;
+    Ok(match options.policies.check(path, &claims) {
+        Some(policy) => NotNull(policy.source().to_string()),
+        None => Null,
+    })
+}
+
+extendr_module! {
+    mod policy;
+    fn check_policies;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("//admin//users/"), "/admin/users");
+        assert_eq!(normalize_path("/./admin/."), "/admin");
+        assert_eq!(normalize_path("/public/../admin"), "/admin");
+        assert_eq!(normalize_path("/../../admin"), "/admin");
+        assert_eq!(normalize_path("/%61dmin"), "/admin");
+        assert_eq!(normalize_path("/public%2F..%2Fadmin"), "/admin");
+        assert_eq!(normalize_path("/\\admin"), "/admin");
+    }
+
+    #[test]
+    fn matches_whole_segments() {
+        let rules = PolicyRules::parse(&[("/admin/".to_string(), "admin".to_string())]).unwrap();
+        let claims = serde_json::json!({});
+        for path in [
+            "/admin",
+            "/admin/",
+            "/admin/users",
+            "//admin",
+            "/./admin",
+            "/x/../admin",
+        ] {
+            assert!(rules.check(path, &claims).is_some(), "{path}");
+        }
+        for path in ["/", "/administrator", "/public/admin"] {
+            assert!(rules.check(path, &claims).is_none(), "{path}");
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::net::IpAddr;
+
+use crate::error::TapLockError;
+
+/// An IP address or CIDR range, e.g. `10.0.0.1` or `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn parse(value: &str) -> Result<Self, TapLockError> {
+        let invalid = || TapLockError::new(format!("Invalid trusted proxy `{value}`"));
+        let (addr, prefix_len) = match value.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (value.trim(), None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        fn masked(bits: u128, prefix_len: u8, width: u8) -> u128 {
+            if prefix_len == 0 {
+                return 0;
+            }
+            bits >> (width - prefix_len)
+        }
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                masked(u32::from(net).into(), self.prefix_len, 32)
+                    == masked(u32::from(addr).into(), self.prefix_len, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                masked(u128::from(net), self.prefix_len, 128)
+                    == masked(u128::from(addr), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The `X-Forwarded-*` headers of a request, as seen by R.
+#[derive(Debug, Default)]
+pub(crate) struct ForwardedRequest {
+    pub remote_addr: Option<String>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+    pub prefix: Option<String>,
+}
+
+// Proxies append to these headers, so earlier values may come from the
+// client itself. Only the right-most one, added by the trusted proxy
+// that sent the request, is used.
+fn last_value(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .and_then(|v| v.rsplit(',').next())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+impl ForwardedRequest {
+    /// Returns the public base URL of the app if the request comes from a
+    /// trusted proxy and carries usable `X-Forwarded-Proto` and
+    /// `X-Forwarded-Host` headers.
+    pub fn public_app_url(&self, trusted_proxies: &[IpRange]) -> Option<String> {
+        let remote_addr = self.remote_addr.as_deref()?.trim().parse::<IpAddr>().ok()?;
+        if !trusted_proxies.iter().any(|net| net.contains(&remote_addr)) {
+            return None;
+        }
+
+        let proto = last_value(&self.proto)?.to_ascii_lowercase();
+        if proto != "http" && proto != "https" {
+            return None;
+        }
+
+        let host = last_value(&self.host)?;
+        let valid_host = host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid_host {
+            return None;
+        }
+
+        let prefix = last_value(&self.prefix).unwrap_or("").trim_matches('/');
+        let valid_prefix = prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.' | '~' | '%'));
+        if !valid_prefix {
+            return None;
+        }
+
+        if prefix.is_empty() {
+            Some(format!("{proto}://{host}"))
+        } else {
+            Some(format!("{proto}://{host}/{prefix}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn request(remote_addr: &str, proto: &str, host: &str) -> ForwardedRequest {
+        ForwardedRequest {
+            remote_addr: Some(remote_addr.to_string()),
+            proto: Some(proto.to_string()),
+            host: Some(host.to_string()),
+            prefix: None,
+        }
+    }
+
+    #[test]
+    fn ipv4_ranges_match_up_to_the_prefix() {
+        let range = IpRange::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains(&ip("10.1.0.0")));
+        assert!(range.contains(&ip("10.1.255.255")));
+        assert!(!range.contains(&ip("10.0.255.255")));
+        assert!(!range.contains(&ip("10.2.0.0")));
+    }
+
+    #[test]
+    fn ipv4_prefix_edges() {
+        let single = IpRange::parse("192.168.1.10").unwrap();
+        assert!(single.contains(&ip("192.168.1.10")));
+        assert!(!single.contains(&ip("192.168.1.11")));
+
+        let all = IpRange::parse("0.0.0.0/0").unwrap();
+        assert!(all.contains(&ip("1.2.3.4")));
+        assert!(all.contains(&ip("255.255.255.255")));
+        assert!(!all.contains(&ip("::1")));
+
+        assert!(IpRange::parse("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn ipv6_ranges_match_up_to_the_prefix() {
+        let range = IpRange::parse("fd00::/8").unwrap();
+        assert!(range.contains(&ip("fd12:3456::1")));
+        assert!(!range.contains(&ip("fe80::1")));
+
+        let single = IpRange::parse("::1/128").unwrap();
+        assert!(single.contains(&ip("::1")));
+        assert!(!single.contains(&ip("::2")));
+
+        assert!(IpRange::parse("::/0").unwrap().contains(&ip("2001:db8::1")));
+        assert!(IpRange::parse("::1/129").is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        let range = IpRange::parse("127.0.0.0/8").unwrap();
+        assert!(range.contains(&ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!(IpRange::parse("localhost").is_err());
+        assert!(IpRange::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn trusts_headers_from_trusted_proxies_only() {
+        let trusted = [IpRange::parse("10.0.0.0/8").unwrap()];
+        assert_eq!(
+            request("10.0.0.5", "https", "app.example.com").public_app_url(&trusted),
+            Some("https://app.example.com".to_string())
+        );
+        assert_eq!(
+            request("203.0.113.7", "https", "app.example.com").public_app_url(&trusted),
+            None
+        );
+        assert_eq!(
+            request("10.0.0.5", "https", "app.example.com").public_app_url(&[]),
+            None
+        );
+    }
+
+    #[test]
+    fn uses_the_value_added_by_the_trusted_proxy() {
+        let trusted = [IpRange::parse("10.0.0.0/8").unwrap()];
+        let request = request("10.0.0.5", "http, https", "evil.com, app.example.com");
+        assert_eq!(
+            request.public_app_url(&trusted),
+            Some("https://app.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_headers() {
+        let trusted = [IpRange::parse("10.0.0.5").unwrap()];
+        assert_eq!(
+            request("10.0.0.5", "ftp", "app.example.com").public_app_url(&trusted),
+            None
+        );
+        assert_eq!(
+            request("10.0.0.5", "https", "app.example.com/@evil.com").public_app_url(&trusted),
+            None
+        );
+        assert_eq!(
+            request("not an ip", "https", "app.example.com").public_app_url(&trusted),
+            None
+        );
+    }
+
+    #[test]
+    fn adds_the_forwarded_prefix() {
+        let trusted = [IpRange::parse("10.0.0.5").unwrap()];
+        let mut request = request("10.0.0.5", "https", "app.example.com");
+        request.prefix = Some("/apps/demo/".to_string());
+        assert_eq!(
+            request.public_app_url(&trusted),
+            Some("https://app.example.com/apps/demo".to_string())
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::TokenResult;
+
+// How long the tokens of a successful refresh are handed out to later
+// requests made with the same refresh token. With rotating refresh
+// tokens, requests sent by the browser before it received the new
+// cookies would otherwise fail with `invalid_grant`.
+const REUSE_WINDOW: Duration = Duration::from_secs(10);
+
+enum Flight {
+    Running(Vec<oneshot::Sender<Arc<TokenResult>>>),
+    Done {
+        result: Arc<TokenResult>,
+        finished_at: Instant,
+    },
+}
+
+/// Coalesces concurrent refreshes of the same refresh token: the first
+/// caller starts the refresh and everyone asking for the same token
+/// while it runs (or shortly after it succeeded) gets its result.
+#[derive(Default)]
+pub(crate) struct RefreshGroup {
+    flights: Mutex<HashMap<Vec<u8>, Flight>>,
+}
+
+// Ends a flight. If the refresh panics or is cancelled before it
+// finishes, dropping the guard removes the flight so that its waiters
+// are told (their senders are dropped) and the next request starts a
+// new refresh.
+struct FlightGuard {
+    group: Arc<RefreshGroup>,
+    key: Option<Vec<u8>>,
+}
+
+impl FlightGuard {
+    fn finish(mut self, result: Arc<TokenResult>) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut flights = self.group.flights();
+        let waiters = match flights.remove(&key) {
+            Some(Flight::Running(waiters)) => waiters,
+            _ => Vec::new(),
+        };
+        // Failures are not shared beyond the callers that were
+        // waiting, so that the next request can try again
+        if result.is_ok() {
+            flights.insert(
+                key,
+                Flight::Done {
+                    result: Arc::clone(&result),
+                    finished_at: Instant::now(),
+                },
+            );
+        }
+        drop(flights);
+        for waiter in waiters {
+            let _ = waiter.send(Arc::clone(&result));
+        }
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let mut flights = self.group.flights();
+            if matches!(flights.get(&key), Some(Flight::Running(_))) {
+                flights.remove(&key);
+            }
+        }
+    }
+}
+
+// Refresh tokens are only kept as hashes
+fn key(refresh_token: &str) -> Vec<u8> {
+    digest(&SHA256, refresh_token.as_bytes()).as_ref().to_vec()
+}
+
+impl RefreshGroup {
+    fn flights(&self) -> std::sync::MutexGuard<'_, HashMap<Vec<u8>, Flight>> {
+        self.flights
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns a receiver for the result of refreshing `refresh_token`,
+    /// spawning `refresh` on `runtime` unless the same token is already
+    /// being (or was just) refreshed.
+    pub fn join<F, Fut>(
+        self: &Arc<Self>,
+        runtime: &tokio::runtime::Runtime,
+        refresh_token: &str,
+        refresh: F,
+    ) -> oneshot::Receiver<Arc<TokenResult>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = TokenResult> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let key = key(refresh_token);
+
+        let mut flights = self.flights();
+        flights.retain(|_, flight| match flight {
+            Flight::Running(_) => true,
+            Flight::Done { finished_at, .. } => finished_at.elapsed() < REUSE_WINDOW,
+        });
+        match flights.get_mut(&key) {
+            Some(Flight::Running(waiters)) => {
+                waiters.push(tx);
+                return rx;
+            }
+            Some(Flight::Done { result, .. }) => {
+                let _ = tx.send(Arc::clone(result));
+                return rx;
+            }
+            None => {
+                flights.insert(key.clone(), Flight::Running(vec![tx]));
+            }
+        }
+        drop(flights);
+
+        // Created before anything that may panic, so that the flight is
+        // never left running
+        let guard = FlightGuard {
+            group: Arc::clone(self),
+            key: Some(key),
+        };
+        let refresh = refresh();
+        runtime.spawn(async move {
+            let result = Arc::new(refresh.await);
+            guard.finish(result);
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TapLockError;
+    use crate::OAuth2Response;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    fn response(access_token: &str) -> TokenResult {
+        Ok(OAuth2Response {
+            access_token: access_token.to_string(),
+            refresh_token: None,
+            fields: serde_json::Value::Null,
+            warnings: Vec::new(),
+        })
+    }
+
+    fn access_token(result: &TokenResult) -> &str {
+        &result.as_ref().unwrap().access_token
+    }
+
+    #[test]
+    fn shares_a_refresh_between_callers() {
+        let runtime = runtime();
+        let group = Arc::new(RefreshGroup::default());
+        let (release, released) = oneshot::channel::<()>();
+        let first = group.join(&runtime, "token", || async move {
+            let _ = released.await;
+            response("new")
+        });
+        let second = group.join(&runtime, "token", || async { response("other") });
+        release.send(()).unwrap();
+
+        let first = runtime.block_on(first).unwrap();
+        let second = runtime.block_on(second).unwrap();
+        assert_eq!(access_token(&first), "new");
+        assert_eq!(access_token(&second), "new");
+
+        // Reused for a while after it succeeded
+        let third = group.join(&runtime, "token", || async { response("other") });
+        assert_eq!(access_token(&runtime.block_on(third).unwrap()), "new");
+    }
+
+    #[test]
+    fn failures_are_not_reused() {
+        let runtime = runtime();
+        let group = Arc::new(RefreshGroup::default());
+        let failed = group.join(&runtime, "token", || async {
+            Err(TapLockError::new("invalid_grant"))
+        });
+        assert!(runtime.block_on(failed).unwrap().is_err());
+
+        let retried = group.join(&runtime, "token", || async { response("new") });
+        assert_eq!(access_token(&runtime.block_on(retried).unwrap()), "new");
+    }
+
+    #[test]
+    fn panics_release_the_waiters() {
+        let runtime = runtime();
+        let group = Arc::new(RefreshGroup::default());
+        let first = group.join(&runtime, "token", || async { panic!("refresh failed") });
+        let second = group.join(&runtime, "token", || async { response("other") });
+        assert!(runtime.block_on(first).is_err());
+        assert!(runtime.block_on(second).is_err());
+
+        let retried = group.join(&runtime, "token", || async { response("new") });
+        assert_eq!(access_token(&runtime.block_on(retried).unwrap()), "new");
+    }
+
+    #[test]
+    fn cancelled_refreshes_release_the_waiters() {
+        let group = Arc::new(RefreshGroup::default());
+        let pending = {
+            let runtime = runtime();
+            let rx = group.join(&runtime, "token", std::future::pending::<TokenResult>);
+            // Dropping the runtime cancels the refresh
+            drop(runtime);
+            rx
+        };
+        assert!(pending.blocking_recv().is_err());
+        assert!(group.flights().is_empty());
+    }
+}
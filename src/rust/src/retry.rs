@@ -0,0 +1,227 @@
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::error::TapLockError;
+
+/// Retry configuration for the HTTP calls made to the identity provider.
+///
+/// Failed attempts are retried with "full jitter" exponential backoff:
+/// before attempt `n` we sleep a random duration between zero and
+/// `min(max_backoff, initial_backoff * 2^(n - 1))`. No attempt is started
+/// (and no running attempt is allowed to continue) past `deadline`,
+/// measured from the first attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(2),
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        if exp.is_zero() {
+            return exp;
+        }
+        rand::thread_rng().gen_range(Duration::ZERO..=exp)
+    }
+
+    /// Runs `op` until it succeeds, returns an error for which
+    /// `should_retry` is false, runs out of attempts or hits the deadline.
+    pub async fn run<T, F, Fut, P>(&self, should_retry: P, mut op: F) -> Result<T, TapLockError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, TapLockError>>,
+        P: Fn(&TapLockError) -> bool,
+    {
+        let deadline = Instant::now()
+            .checked_add(self.deadline)
+            .ok_or_else(|| TapLockError::new("`retry_deadline` is too large"))?;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match tokio::time::timeout_at(deadline, op()).await {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(err)) => err,
+                Err(_) => return Err(TapLockError::DeadlineExceeded(attempt)),
+            };
+
+            if attempt >= self.max_attempts || !should_retry(&err) {
+                return Err(err);
+            }
+
+            match Instant::now().checked_add(self.backoff(attempt)) {
+                Some(wake_up) if wake_up < deadline => tokio::time::sleep_until(wake_up).await,
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Runs a request to the token endpoint of the provider.
+    ///
+    /// Authorization codes are single use and refresh tokens may be
+    /// rotated on use, so a request that reached the endpoint may have
+    /// consumed them even if its response was lost. It is only retried
+    /// when it failed to connect.
+    pub async fn token_request<T, F, Fut>(&self, op: F) -> Result<T, TapLockError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, TapLockError>>,
+    {
+        self.run(TapLockError::is_connect, op).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn policy(max_attempts: u32, deadline: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            deadline,
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped_exponential() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            deadline: Duration::from_secs(10),
+        };
+        for _ in 0..100 {
+            assert!(policy.backoff(1) <= Duration::from_millis(100));
+            assert!(policy.backoff(2) <= Duration::from_millis(200));
+            assert!(policy.backoff(3) <= Duration::from_millis(300));
+            assert!(policy.backoff(40) <= Duration::from_millis(300));
+        }
+        let no_backoff = RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            ..policy
+        };
+        assert_eq!(no_backoff.backoff(5), Duration::ZERO);
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let attempts = Cell::new(0);
+        let result = block_on(policy(3, Duration::from_secs(5)).run(
+            |_| true,
+            || {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    match attempt {
+                        3 => Ok(attempt),
+                        _ => Err(TapLockError::new("failed")),
+                    }
+                }
+            },
+        ));
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = block_on(policy(2, Duration::from_secs(5)).run(
+            |_| true,
+            || {
+                attempts.set(attempts.get() + 1);
+                async { Err(TapLockError::new("failed")) }
+            },
+        ));
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_definitive_errors() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = block_on(policy(5, Duration::from_secs(5)).run(
+            |_| false,
+            || {
+                attempts.set(attempts.get() + 1);
+                async { Err(TapLockError::new("failed")) }
+            },
+        ));
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn cancels_attempts_past_the_deadline() {
+        let result: Result<(), _> = block_on(policy(5, Duration::from_millis(20)).run(
+            |_| true,
+            || async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            },
+        ));
+        assert!(matches!(result, Err(TapLockError::DeadlineExceeded(1))));
+    }
+
+    #[test]
+    fn token_requests_are_only_retried_on_connect_errors() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> =
+            block_on(policy(3, Duration::from_secs(5)).token_request(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err(TapLockError::new("invalid_grant")) }
+            }));
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        // Nothing listens on the discard port
+        let attempts = Cell::new(0);
+        let client = reqwest::Client::new();
+        let result: Result<(), _> =
+            block_on(policy(3, Duration::from_secs(5)).token_request(|| {
+                attempts.set(attempts.get() + 1);
+                let request = client.post("http://127.0.0.1:9/token").send();
+                async move {
+                    request.await?;
+                    Ok(())
+                }
+            }));
+        assert!(result.unwrap_err().is_connect());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn rejects_deadlines_that_overflow() {
+        let result: Result<(), _> =
+            block_on(policy(1, Duration::MAX).run(|_| true, || async { Ok(()) }));
+        assert!(result.is_err());
+    }
+}
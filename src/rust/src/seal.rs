@@ -0,0 +1,134 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use extendr_api::prelude::*;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::error::TapLockError;
+
+const KEY_LEN: usize = 32;
+
+/// The keys used to seal cookie values. Values are always sealed with
+/// the first (current) key and opened with any of them, so a new key
+/// can be put in front while the previous ones are still accepted for
+/// the lifetime of the cookies they sealed.
+#[derive(Clone)]
+pub(crate) struct KeyRing {
+    keys: Vec<[u8; KEY_LEN]>,
+}
+
+// Never print the keys
+impl std::fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyRing")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+fn decode_key(key: &str) -> Option<[u8; KEY_LEN]> {
+    let key = key.trim();
+    let bytes = STANDARD
+        .decode(key)
+        .or_else(|_| URL_SAFE.decode(key))
+        .or_else(|_| URL_SAFE_NO_PAD.decode(key))
+        .ok()?;
+    bytes.try_into().ok()
+}
+
+fn aead_key(key: &[u8; KEY_LEN]) -> LessSafeKey {
+    // Only fails if the key has the wrong length
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("AES-256 keys are 32 bytes"))
+}
+
+impl KeyRing {
+    /// Parses base64 encoded 256 bit keys, current key first.
+    pub fn parse(keys: &[String]) -> std::result::Result<Self, TapLockError> {
+        if keys.is_empty() {
+            return Err(TapLockError::new(
+                "`cookie_keys` must contain at least one key",
+            ));
+        }
+        let keys = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                decode_key(key).ok_or_else(|| {
+                    TapLockError::new(format!(
+                        "Cookie key {} is not a base64 encoded 256 bit key, see `new_cookie_key()`",
+                        i + 1
+                    ))
+                })
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self { keys })
+    }
+
+    /// Encrypts `value` with the current key. The cookie name is
+    /// authenticated too, so a sealed value cannot be moved to another
+    /// cookie. The result is `base64url(nonce || ciphertext || tag)`.
+    pub fn seal(&self, name: &str, value: &str) -> std::result::Result<String, TapLockError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| TapLockError::new("Unable to generate a nonce"))?;
+
+        let mut in_out = value.as_bytes().to_vec();
+        aead_key(&self.keys[0])
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| TapLockError::new("Unable to seal the cookie"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&in_out);
+        Ok(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    /// Decrypts a value built by [`KeyRing::seal`] with any of the keys.
+    /// Fails with [`TapLockError::CookieTampered`] if the value was
+    /// modified, belongs to another cookie or was sealed with a key that
+    /// is no longer in the ring.
+    pub fn open(&self, name: &str, sealed: &str) -> std::result::Result<String, TapLockError> {
+        let tampered = || TapLockError::CookieTampered(name.to_string());
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).map_err(|_| tampered())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(tampered());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        for key in &self.keys {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| tampered())?;
+            let mut in_out = ciphertext.to_vec();
+            if let Ok(plaintext) =
+                aead_key(key).open_in_place(nonce, Aad::from(name.as_bytes()), &mut in_out)
+            {
+                return String::from_utf8(plaintext.to_vec()).map_err(|_| tampered());
+            }
+        }
+        Err(tampered())
+    }
+}
+
+/// @title Generate a cookie key
+/// @description Generates a random 256 bit key to seal cookies with
+///   (see the `cookie_keys` option of [runtime_options()]). Store it
+///   as a secret, e.g. in an environment variable.
+///
+/// @return A base64 encoded key
+/// @export
+#[extendr]
+fn new_cookie_key() -> Result<String> {
+    let mut key = [0u8; KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| TapLockError::new("Unable to generate a key"))?;
+    Ok(STANDARD.encode(key))
+}
+
+extendr_module! {
+    mod seal;
+    fn new_cookie_key;
+}
@@ -0,0 +1,745 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::TapLockError;
+
+// 256 bits of randomness, 43 characters once encoded
+const SESSION_ID_LEN: usize = 32;
+
+/// The tokens of a logged in user, kept on the server. Only the id goes
+/// into the session cookie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Session {
+    pub id: String,
+    pub subject: Option<String>,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Unix time of the login
+    pub created_at: u64,
+    /// Unix time of the last request made with the session
+    pub last_seen: u64,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn new_session_id() -> std::result::Result<String, TapLockError> {
+    let mut id = [0u8; SESSION_ID_LEN];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| TapLockError::new("Unable to generate a session id"))?;
+    Ok(URL_SAFE_NO_PAD.encode(id))
+}
+
+// Session ids come from cookies, and are used as file names by
+// `FileStore`, so anything but base64url is rejected
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl Session {
+    pub fn new(
+        subject: Option<String>,
+        access_token: String,
+        refresh_token: Option<String>,
+    ) -> std::result::Result<Self, TapLockError> {
+        let now = now();
+        Ok(Self {
+            id: new_session_id()?,
+            subject,
+            access_token,
+            refresh_token,
+            created_at: now,
+            last_seen: now,
+        })
+    }
+}
+
+/// Where sessions are kept. Implementations only store and retrieve
+/// sessions, timeouts are enforced by [`Sessions`].
+pub(crate) trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> std::result::Result<Option<Session>, TapLockError>;
+    fn save(&self, session: &Session) -> std::result::Result<(), TapLockError>;
+    /// Records the last activity of a session without writing its
+    /// tokens, which may have been replaced since it was loaded.
+    fn touch(&self, id: &str, last_seen: u64) -> std::result::Result<(), TapLockError>;
+    fn remove(&self, id: &str) -> std::result::Result<(), TapLockError>;
+    fn list(&self) -> std::result::Result<Vec<Session>, TapLockError>;
+}
+
+/// Sessions kept in the memory of the R process. They are lost when the
+/// app restarts and are not shared between processes.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl MemoryStore {
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        // A panic while holding the lock cannot leave a `HashMap` in an
+        // inconsistent state
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> std::result::Result<Option<Session>, TapLockError> {
+        Ok(self.sessions().get(id).cloned())
+    }
+
+    fn save(&self, session: &Session) -> std::result::Result<(), TapLockError> {
+        self.sessions().insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+
+    fn touch(&self, id: &str, last_seen: u64) -> std::result::Result<(), TapLockError> {
+        if let Some(session) = self.sessions().get_mut(id) {
+            session.last_seen = session.last_seen.max(last_seen);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> std::result::Result<(), TapLockError> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+
+    fn list(&self) -> std::result::Result<Vec<Session>, TapLockError> {
+        Ok(self.sessions().values().cloned().collect())
+    }
+}
+
+/// Sessions kept as JSON files in a directory, one per session. The
+/// directory can be shared by several R processes (e.g. behind a load
+/// balancer). On Unix it is created readable by the app only, as are
+/// the session files. Database backed stores (e.g. SQLite) are out of
+/// scope.
+pub(crate) struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: PathBuf) -> std::result::Result<Self, TapLockError> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+            builder.mode(0o700).create(&dir)?;
+            // An existing directory keeps its mode otherwise
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        #[cfg(not(unix))]
+        builder.create(&dir)?;
+        Ok(Self { dir })
+    }
+
+    // Ids are checked by `Sessions` already, but they end up in file
+    // names so the store does not rely on it
+    fn path(&self, id: &str) -> std::result::Result<PathBuf, TapLockError> {
+        if !is_valid_id(id) {
+            return Err(TapLockError::new("Invalid session id"));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    // The last activity is kept in a file of its own next to the
+    // session, so that recording it never rewrites the tokens
+    fn seen_path(&self, id: &str) -> std::result::Result<PathBuf, TapLockError> {
+        Ok(self.path(id)?.with_extension("seen"))
+    }
+
+    fn with_last_seen(&self, mut session: Session) -> Session {
+        let last_seen = self
+            .seen_path(&session.id)
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|seen| seen.trim().parse().ok());
+        if let Some(last_seen) = last_seen {
+            session.last_seen = session.last_seen.max(last_seen);
+        }
+        session
+    }
+}
+
+fn remove_if_exists(path: &Path) -> std::result::Result<(), TapLockError> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(bytes)
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> std::result::Result<Option<Session>, TapLockError> {
+        match std::fs::read(self.path(id)?) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)
+                .ok()
+                .map(|session| self.with_last_seen(session))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, session: &Session) -> std::result::Result<(), TapLockError> {
+        let path = self.path(&session.id)?;
+        let bytes = serde_json::to_vec(session)
+            .map_err(|err| TapLockError::new(format!("Unable to serialize session: {err}")))?;
+        // Write then rename so readers never see a partial file
+        let tmp = self.dir.join(format!(".{}.tmp", session.id));
+        write_private(&tmp, &bytes)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn touch(&self, id: &str, last_seen: u64) -> std::result::Result<(), TapLockError> {
+        let path = self.seen_path(id)?;
+        let tmp = self.dir.join(format!(".{id}.seen.tmp"));
+        write_private(&tmp, last_seen.to_string().as_bytes())?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> std::result::Result<(), TapLockError> {
+        remove_if_exists(&self.path(id)?)?;
+        remove_if_exists(&self.seen_path(id)?)
+    }
+
+    fn list(&self) -> std::result::Result<Vec<Session>, TapLockError> {
+        let mut sessions = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                // Sessions removed or being written concurrently are skipped
+                if let Some(session) = std::fs::read(&path)
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                {
+                    sessions.push(self.with_last_seen(session));
+                }
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+// Activity is only recorded once per minute, so that every request does
+// not have to write to the store or send a new cookie
+const ACTIVITY_RESOLUTION: u64 = 60;
+
+/// The idle and absolute timeouts of a session.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SessionTimeouts {
+    pub idle: Option<Duration>,
+    pub absolute: Option<Duration>,
+}
+
+impl SessionTimeouts {
+    pub fn is_enabled(&self) -> bool {
+        self.idle.is_some() || self.absolute.is_some()
+    }
+
+    pub fn is_expired(&self, created_at: u64, last_seen: u64, now: u64) -> bool {
+        let exceeded = |since: u64, timeout: Option<Duration>| {
+            timeout.is_some_and(|timeout| now.saturating_sub(since) >= timeout.as_secs())
+        };
+        exceeded(last_seen, self.idle) || exceeded(created_at, self.absolute)
+    }
+
+    /// Seconds until the absolute timeout, if any.
+    pub fn remaining(&self, created_at: u64, now: u64) -> Option<u64> {
+        self.absolute
+            .map(|absolute| (created_at + absolute.as_secs()).saturating_sub(now))
+    }
+}
+
+fn is_stale(last_seen: u64, now: u64) -> bool {
+    now.saturating_sub(last_seen) >= ACTIVITY_RESOLUTION
+}
+
+/// The login time and last activity of a session whose tokens are kept
+/// in cookies, stored in a sealed cookie as `<created_at>.<last_seen>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SessionTimes {
+    pub created_at: u64,
+    pub last_seen: u64,
+}
+
+impl SessionTimes {
+    pub fn start() -> Self {
+        let now = now();
+        Self {
+            created_at: now,
+            last_seen: now,
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let (created_at, last_seen) = value.split_once('.')?;
+        Some(Self {
+            created_at: created_at.parse().ok()?,
+            last_seen: last_seen.parse().ok()?,
+        })
+    }
+
+    /// Checks the timeouts and records the activity. Returns `None` if
+    /// the session has timed out, and whether the times changed.
+    pub fn touch(self, timeouts: &SessionTimeouts) -> Option<(Self, bool)> {
+        let now = now();
+        if timeouts.is_expired(self.created_at, self.last_seen, now) {
+            return None;
+        }
+        if !is_stale(self.last_seen, now) {
+            return Some((self, false));
+        }
+        Some((
+            Self {
+                last_seen: now,
+                ..self
+            },
+            true,
+        ))
+    }
+}
+
+impl std::fmt::Display for SessionTimes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.created_at, self.last_seen)
+    }
+}
+
+/// Session settings as configured in `runtime_options()`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionOptions {
+    /// `None` keeps the tokens in cookies
+    pub store: Option<String>,
+    pub dir: Option<PathBuf>,
+    pub timeouts: SessionTimeouts,
+}
+
+impl SessionOptions {
+    pub fn open(&self) -> std::result::Result<Option<Sessions>, TapLockError> {
+        let store: Arc<dyn SessionStore> = match (self.store.as_deref(), &self.dir) {
+            (None, _) => return Ok(None),
+            (Some("memory"), _) => Arc::new(MemoryStore::default()),
+            (Some("file"), Some(dir)) => Arc::new(FileStore::new(dir.clone())?),
+            (Some("file"), None) => {
+                return Err(TapLockError::new(
+                    "`session_dir` is required with `session_store = \"file\"`",
+                ))
+            }
+            (Some(store), _) => {
+                return Err(TapLockError::new(format!(
+                    "Invalid session store `{store}`, expected `memory` or `file`"
+                )))
+            }
+        };
+        Ok(Some(Sessions {
+            store,
+            timeouts: self.timeouts,
+        }))
+    }
+}
+
+/// A session store along with the timeouts of the runtime.
+#[derive(Clone)]
+pub(crate) struct Sessions {
+    store: Arc<dyn SessionStore>,
+    timeouts: SessionTimeouts,
+}
+
+impl Sessions {
+    fn is_expired(&self, session: &Session, now: u64) -> bool {
+        self.timeouts
+            .is_expired(session.created_at, session.last_seen, now)
+    }
+
+    /// Stores the tokens of a new login and returns the session.
+    pub fn create(
+        &self,
+        subject: Option<String>,
+        access_token: String,
+        refresh_token: Option<String>,
+    ) -> std::result::Result<Session, TapLockError> {
+        let session = Session::new(subject, access_token, refresh_token)?;
+        self.store.save(&session)?;
+        Ok(session)
+    }
+
+    /// Returns the session and records the activity, or `None` if it does
+    /// not exist or has timed out (in which case it is removed).
+    pub fn get(&self, id: &str) -> std::result::Result<Option<Session>, TapLockError> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        let Some(mut session) = self.store.load(id)? else {
+            return Ok(None);
+        };
+        let now = now();
+        if self.is_expired(&session, now) {
+            self.store.remove(id)?;
+            return Ok(None);
+        }
+        if is_stale(session.last_seen, now) {
+            // Saving the copy loaded above could undo a refresh that
+            // replaced the tokens in the meantime
+            self.store.touch(id, now)?;
+            session.last_seen = now;
+        }
+        Ok(Some(session))
+    }
+
+    /// Replaces the tokens of a session after a refresh. The login time
+    /// is kept, so refreshing does not extend the absolute timeout.
+    pub fn update(
+        &self,
+        id: &str,
+        access_token: String,
+        refresh_token: Option<String>,
+    ) -> std::result::Result<Option<Session>, TapLockError> {
+        let Some(mut session) = self.get(id)? else {
+            return Ok(None);
+        };
+        session.access_token = access_token;
+        // Providers only return a refresh token when they rotate it
+        if refresh_token.is_some() {
+            session.refresh_token = refresh_token;
+        }
+        self.store.save(&session)?;
+        Ok(Some(session))
+    }
+
+    pub fn invalidate(&self, id: &str) -> std::result::Result<(), TapLockError> {
+        if !is_valid_id(id) {
+            return Ok(());
+        }
+        self.store.remove(id)
+    }
+
+    /// The active sessions of a user, oldest first. Timed out sessions
+    /// found along the way are removed.
+    pub fn list(&self, subject: &str) -> std::result::Result<Vec<Session>, TapLockError> {
+        let now = now();
+        let mut sessions = Vec::new();
+        for session in self.store.list()? {
+            if self.is_expired(&session, now) {
+                self.store.remove(&session.id)?;
+            } else if session.subject.as_deref() == Some(subject) {
+                sessions.push(session);
+            }
+        }
+        sessions.sort_by_key(|session| session.created_at);
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A session store in a directory of its own, removed on drop
+    struct TempStore {
+        dir: PathBuf,
+        sessions: Sessions,
+    }
+
+    impl TempStore {
+        fn new(timeouts: SessionTimeouts) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("taplock-{}", new_session_id().unwrap()))
+                .join("sessions");
+            let sessions = SessionOptions {
+                store: Some("file".to_string()),
+                dir: Some(dir.clone()),
+                timeouts,
+            }
+            .open()
+            .unwrap()
+            .unwrap();
+            Self { dir, sessions }
+        }
+
+        fn file_store(&self) -> FileStore {
+            FileStore::new(self.dir.clone()).unwrap()
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.dir.parent().unwrap());
+        }
+    }
+
+    fn idle(seconds: u64) -> SessionTimeouts {
+        SessionTimeouts {
+            idle: Some(Duration::from_secs(seconds)),
+            absolute: None,
+        }
+    }
+
+    #[test]
+    fn creates_and_gets_sessions() {
+        let store = TempStore::new(SessionTimeouts::default());
+        let session = store
+            .sessions
+            .create(
+                Some("alice".into()),
+                "access".into(),
+                Some("refresh".into()),
+            )
+            .unwrap();
+        assert_eq!(session.id.len(), 43);
+
+        let loaded = store.sessions.get(&session.id).unwrap().unwrap();
+        assert_eq!(loaded.subject.as_deref(), Some("alice"));
+        assert_eq!(loaded.access_token, "access");
+        assert_eq!(loaded.refresh_token.as_deref(), Some("refresh"));
+        assert!(store.sessions.get("unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn updates_the_tokens_of_a_session() {
+        let store = TempStore::new(SessionTimeouts::default());
+        let session = store
+            .sessions
+            .create(None, "access".into(), Some("refresh".into()))
+            .unwrap();
+
+        let updated = store
+            .sessions
+            .update(&session.id, "new access".into(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.access_token, "new access");
+        assert_eq!(updated.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(updated.created_at, session.created_at);
+
+        let loaded = store.sessions.get(&session.id).unwrap().unwrap();
+        assert_eq!(loaded.access_token, "new access");
+        assert!(store
+            .sessions
+            .update("unknown", "access".into(), None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn lists_the_sessions_of_a_subject() {
+        let store = TempStore::new(SessionTimeouts::default());
+        let first = store
+            .sessions
+            .create(Some("alice".into()), "a".into(), None)
+            .unwrap();
+        let second = store
+            .sessions
+            .create(Some("alice".into()), "b".into(), None)
+            .unwrap();
+        store
+            .sessions
+            .create(Some("bob".into()), "c".into(), None)
+            .unwrap();
+
+        let mut ids: Vec<_> = store
+            .sessions
+            .list("alice")
+            .unwrap()
+            .into_iter()
+            .map(|session| session.id)
+            .collect();
+        ids.sort();
+        let mut expected = vec![first.id, second.id];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert!(store.sessions.list("carol").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalidates_sessions() {
+        let store = TempStore::new(SessionTimeouts::default());
+        let session = store.sessions.create(None, "a".into(), None).unwrap();
+        store.sessions.invalidate(&session.id).unwrap();
+        assert!(store.sessions.get(&session.id).unwrap().is_none());
+        assert!(!store.dir.join(format!("{}.json", session.id)).exists());
+        // Unknown and invalid ids are ignored
+        store.sessions.invalidate(&session.id).unwrap();
+        store.sessions.invalidate("../escape").unwrap();
+    }
+
+    #[test]
+    fn expired_sessions_are_removed() {
+        let store = TempStore::new(idle(600));
+        let mut session = Session::new(Some("alice".into()), "a".into(), None).unwrap();
+        session.last_seen -= 600;
+        store.file_store().save(&session).unwrap();
+
+        assert!(store.sessions.get(&session.id).unwrap().is_none());
+        assert!(store.file_store().load(&session.id).unwrap().is_none());
+
+        let mut session = Session::new(Some("alice".into()), "a".into(), None).unwrap();
+        session.last_seen -= 599;
+        store.file_store().save(&session).unwrap();
+        assert!(store.sessions.get(&session.id).unwrap().is_some());
+    }
+
+    #[test]
+    fn listing_removes_expired_sessions() {
+        let store = TempStore::new(idle(600));
+        let mut expired = Session::new(Some("alice".into()), "a".into(), None).unwrap();
+        expired.last_seen -= 600;
+        store.file_store().save(&expired).unwrap();
+        let active = store
+            .sessions
+            .create(Some("alice".into()), "b".into(), None)
+            .unwrap();
+
+        let sessions = store.sessions.list("alice").unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, active.id);
+        assert!(store.file_store().load(&expired.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_ids_escaping_the_directory() {
+        let store = TempStore::new(SessionTimeouts::default());
+        let file_store = store.file_store();
+        assert!(file_store.load("../escape").is_err());
+        assert!(file_store.remove("a/b").is_err());
+        let mut session = Session::new(None, "a".into(), None).unwrap();
+        session.id = "../escape".into();
+        assert!(file_store.save(&session).is_err());
+        assert!(store.sessions.get("../escape").unwrap().is_none());
+    }
+
+    // Runs `hook` once, right after the first session is loaded, to
+    // interleave another request with the one loading it
+    struct InterleavedStore {
+        inner: Arc<dyn SessionStore>,
+        hook: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    }
+
+    impl SessionStore for InterleavedStore {
+        fn load(&self, id: &str) -> std::result::Result<Option<Session>, TapLockError> {
+            let session = self.inner.load(id)?;
+            let hook = self.hook.lock().unwrap().take();
+            if let Some(hook) = hook {
+                hook();
+            }
+            Ok(session)
+        }
+        fn save(&self, session: &Session) -> std::result::Result<(), TapLockError> {
+            self.inner.save(session)
+        }
+        fn touch(&self, id: &str, last_seen: u64) -> std::result::Result<(), TapLockError> {
+            self.inner.touch(id, last_seen)
+        }
+        fn remove(&self, id: &str) -> std::result::Result<(), TapLockError> {
+            self.inner.remove(id)
+        }
+        fn list(&self) -> std::result::Result<Vec<Session>, TapLockError> {
+            self.inner.list()
+        }
+    }
+
+    fn recording_activity_keeps_a_concurrent_refresh(inner: Arc<dyn SessionStore>) {
+        let refreshing = Sessions {
+            store: Arc::clone(&inner),
+            timeouts: SessionTimeouts::default(),
+        };
+        let mut session = Session::new(None, "old access".into(), Some("old".into())).unwrap();
+        session.last_seen -= ACTIVITY_RESOLUTION;
+        inner.save(&session).unwrap();
+
+        let id = session.id.clone();
+        let sessions = Sessions {
+            store: Arc::new(InterleavedStore {
+                inner: Arc::clone(&inner),
+                hook: Mutex::new(Some(Box::new(move || {
+                    refreshing
+                        .update(&id, "new access".into(), Some("rotated".into()))
+                        .unwrap();
+                }))),
+            }),
+            timeouts: SessionTimeouts::default(),
+        };
+        // Loads the stale session, the refresh runs, then the activity
+        // is recorded
+        let seen = sessions.get(&session.id).unwrap().unwrap();
+        assert_eq!(seen.refresh_token.as_deref(), Some("old"));
+
+        let loaded = inner.load(&session.id).unwrap().unwrap();
+        assert_eq!(loaded.access_token, "new access");
+        assert_eq!(loaded.refresh_token.as_deref(), Some("rotated"));
+        assert!(!is_stale(loaded.last_seen, now()));
+    }
+
+    #[test]
+    fn activity_does_not_undo_refreshes_in_memory() {
+        recording_activity_keeps_a_concurrent_refresh(Arc::new(MemoryStore::default()));
+    }
+
+    #[test]
+    fn activity_does_not_undo_refreshes_in_files() {
+        let store = TempStore::new(SessionTimeouts::default());
+        recording_activity_keeps_a_concurrent_refresh(Arc::new(store.file_store()));
+    }
+
+    #[test]
+    fn removes_the_activity_with_the_session() {
+        let store = TempStore::new(SessionTimeouts::default());
+        let file_store = store.file_store();
+        let session = Session::new(None, "a".into(), None).unwrap();
+        file_store.save(&session).unwrap();
+        file_store
+            .touch(&session.id, session.last_seen + 120)
+            .unwrap();
+        assert_eq!(
+            file_store.load(&session.id).unwrap().unwrap().last_seen,
+            session.last_seen + 120
+        );
+        assert_eq!(
+            file_store.list().unwrap()[0].last_seen,
+            session.last_seen + 120
+        );
+
+        file_store.remove(&session.id).unwrap();
+        assert_eq!(std::fs::read_dir(&store.dir).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_sessions_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let store = TempStore::new(SessionTimeouts::default());
+        let session = store.sessions.create(None, "a".into(), None).unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&store.dir), 0o700);
+        assert_eq!(mode(&store.dir.join(format!("{}.json", session.id))), 0o600);
+    }
+}
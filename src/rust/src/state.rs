@@ -0,0 +1,174 @@
+use oauth2::{url::Url, CsrfToken};
+
+// The state sent to the provider is `<nonce>.<url encoded return path>`.
+// The nonce comes from `CsrfToken::new_random` which is base64url
+// encoded and therefore never contains a `.`.
+const SEPARATOR: char = '.';
+
+/// A random value binding a login to the browser that started it. It
+/// is stored in a cookie and carried in the `state`.
+pub fn new_nonce() -> String {
+    CsrfToken::new_random().secret().clone()
+}
+
+/// Builds the `state` parameter of the authorization URL, carrying the
+/// path the user should be sent back to after login.
+pub fn new_state(nonce: &str, return_to: Option<&str>) -> String {
+    match return_to {
+        Some(path) => format!("{nonce}{SEPARATOR}{}", urlencoding::encode(path)),
+        None => nonce.to_string(),
+    }
+}
+
+/// Checks that `state` was built with `nonce`, i.e. that the login was
+/// started by the browser holding the nonce cookie.
+pub fn verify(state: &str, nonce: &str) -> bool {
+    let state_nonce = state
+        .split_once(SEPARATOR)
+        .map_or(state, |(nonce, _)| nonce);
+    !nonce.is_empty()
+        && state_nonce.len() == nonce.len()
+        && state_nonce
+            .bytes()
+            .zip(nonce.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Extracts the return path from a `state` and resolves it against
+/// `app_url`. Returns `None` if there is no return path or if it would
+/// leave the app (different origin or outside of the app's path).
+pub fn return_url(state: &str, app_url: &str) -> Option<String> {
+    let (_, path) = state.split_once(SEPARATOR)?;
+    let path = urlencoding::decode(path).ok()?;
+    resolve_return_path(&path, app_url)
+}
+
+/// The return path of a `state`, if [`return_url`] accepts it.
+pub fn return_path(state: &str, app_url: &str) -> Option<String> {
+    let (_, path) = state.split_once(SEPARATOR)?;
+    let path = urlencoding::decode(path).ok()?;
+    resolve_return_path(&path, app_url)?;
+    Some(path.into_owned())
+}
+
+fn resolve_return_path(path: &str, app_url: &str) -> Option<String> {
+    // Only plain absolute paths. `//host` and `/\host` are treated by
+    // browsers as protocol-relative URLs.
+    if !path.starts_with('/')
+        || path.starts_with("//")
+        || path.contains('\\')
+        || path.chars().any(char::is_control)
+    {
+        return None;
+    }
+
+    let app_url = app_url.trim_end_matches('/');
+    let base = Url::parse(&format!("{app_url}/")).ok()?;
+    let url = Url::parse(&format!("{app_url}{path}")).ok()?;
+
+    if url.origin() != base.origin() || !url.path().starts_with(base.path()) {
+        return None;
+    }
+
+    Some(url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_URL: &str = "https://app.example.com";
+
+    fn return_to(path: &str) -> Option<String> {
+        return_url(&new_state(&new_nonce(), Some(path)), APP_URL)
+    }
+
+    #[test]
+    fn keeps_paths_within_the_app() {
+        assert_eq!(
+            return_to("/reports?year=2024#top"),
+            Some("https://app.example.com/reports?year=2024#top".to_string())
+        );
+        assert_eq!(
+            return_url(&new_state("nonce", Some("/a")), "https://app.example.com/"),
+            Some("https://app.example.com/a".to_string())
+        );
+    }
+
+    #[test]
+    fn ignores_states_without_a_path() {
+        assert_eq!(return_url(&new_state(&new_nonce(), None), APP_URL), None);
+    }
+
+    #[test]
+    fn rejects_absolute_urls() {
+        assert_eq!(return_to("https://evil.com/"), None);
+        assert_eq!(return_to("http://app.example.com/"), None);
+        assert_eq!(return_to("javascript:alert(1)"), None);
+    }
+
+    #[test]
+    fn rejects_protocol_relative_urls() {
+        assert_eq!(return_to("//evil.com"), None);
+        assert_eq!(return_to("/\\evil.com"), None);
+        assert_eq!(return_to("\\\\evil.com"), None);
+        assert_eq!(return_to("/\t/evil.com"), None);
+    }
+
+    #[test]
+    fn rejects_encoded_variants() {
+        // The path is encoded once in the state, a second encoding is
+        // kept as-is and never decoded into a separator
+        assert_eq!(return_url("nonce.%2F%2Fevil.com", APP_URL), None);
+        assert_eq!(return_url("nonce.%2F%5Cevil.com", APP_URL), None);
+        assert_eq!(return_url("nonce.%252F%252Fevil.com", APP_URL), None);
+        assert_eq!(
+            return_to("/%2F%2Fevil.com"),
+            Some("https://app.example.com/%2F%2Fevil.com".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_other_origins_and_paths_outside_the_app() {
+        assert_eq!(return_to("@evil.com"), None);
+        assert_eq!(
+            return_url(
+                &new_state("nonce", Some("/../admin")),
+                "https://example.com/app"
+            ),
+            None
+        );
+        assert_eq!(
+            return_url(
+                &new_state("nonce", Some("/page")),
+                "https://example.com/app"
+            ),
+            Some("https://example.com/app/page".to_string())
+        );
+    }
+
+    #[test]
+    fn returns_the_path_only_if_it_stays_within_the_app() {
+        let state = new_state("nonce", Some("/reports?year=2024"));
+        assert_eq!(
+            return_path(&state, APP_URL),
+            Some("/reports?year=2024".to_string())
+        );
+        assert_eq!(
+            return_path(&new_state("nonce", Some("//evil.com")), APP_URL),
+            None
+        );
+        assert_eq!(return_path("nonce", APP_URL), None);
+    }
+
+    #[test]
+    fn verifies_the_nonce_of_the_state() {
+        let nonce = new_nonce();
+        assert!(verify(&new_state(&nonce, Some("/page")), &nonce));
+        assert!(verify(&new_state(&nonce, None), &nonce));
+        assert!(!verify(&new_state(&new_nonce(), Some("/page")), &nonce));
+        assert!(!verify(&format!("{nonce}x"), &nonce));
+        assert!(!verify("", ""));
+    }
+}
@@ -0,0 +1,163 @@
+use jsonwebtoken::errors::ErrorKind;
+use ring::digest::{digest, SHA256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::TapLockError;
+
+// How long a rejected token is rejected without checking it again
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+type Key = [u8; 32];
+
+enum Entry {
+    /// The claims of a verified token, until it expires
+    Valid {
+        claims: serde_json::Value,
+        valid_until: u64,
+    },
+    /// The message of the error the token was rejected with
+    Rejected { message: String, until: Instant },
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, (Entry, u64)>,
+    // Last use of each entry, oldest first
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &Key) {
+        if let Some((_, last_used)) = self.entries.get_mut(key) {
+            self.recency.remove(last_used);
+            self.tick += 1;
+            *last_used = self.tick;
+            self.recency.insert(self.tick, *key);
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.recency.remove(&last_used);
+        }
+    }
+}
+
+/// What the cache knows about a token.
+pub(crate) enum Cached {
+    Valid(serde_json::Value),
+    Rejected(TapLockError),
+}
+
+/// Remembers the outcome of verifying tokens, so that the signature of
+/// a token is not checked again on every request. Verified tokens are
+/// kept until they expire and rejected ones for a short while. The least
+/// recently used entries are evicted past `capacity`.
+pub(crate) struct TokenCache {
+    inner: Mutex<Inner>,
+    capacity: usize,
+}
+
+fn key(token: &str) -> Key {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(digest(&SHA256, token.as_bytes()).as_ref());
+    key
+}
+
+// Only rejections that will not change by checking again. A token that
+// is not valid yet may become valid, and an unknown `kid` may show up
+// once the signing keys are refreshed.
+fn is_definitive(err: &TapLockError) -> bool {
+    match err {
+        TapLockError::JwtError(err) => !matches!(err.kind(), ErrorKind::ImmatureSignature),
+        TapLockError::HostedDomainNotAllowed(_)
+        | TapLockError::OrganizationNotAllowed(_)
+        | TapLockError::TokenTooOld
+        | TapLockError::UnauthorizedParty(_) => true,
+        _ => false,
+    }
+}
+
+impl TokenCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            capacity,
+        }
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, token: &str, now: u64) -> Option<Cached> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let key = key(token);
+        let mut inner = self.inner();
+        let cached = match &inner.entries.get(&key)?.0 {
+            Entry::Valid {
+                claims,
+                valid_until,
+            } if now < *valid_until => Some(Cached::Valid(claims.clone())),
+            Entry::Rejected { message, until } if Instant::now() < *until => Some(
+                Cached::Rejected(TapLockError::TokenRejected(message.clone())),
+            ),
+            _ => None,
+        };
+        match cached {
+            Some(_) => inner.touch(&key),
+            None => inner.remove(&key),
+        }
+        cached
+    }
+
+    /// Records the outcome of verifying `token`. Verified tokens are
+    /// kept until `valid_until`.
+    pub fn insert(
+        &self,
+        token: &str,
+        result: &std::result::Result<serde_json::Value, TapLockError>,
+        valid_until: Option<u64>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = match result {
+            Ok(claims) => {
+                let Some(valid_until) = valid_until else {
+                    return;
+                };
+                Entry::Valid {
+                    claims: claims.clone(),
+                    valid_until,
+                }
+            }
+            Err(err) if is_definitive(err) => Entry::Rejected {
+                message: err.to_string(),
+                until: Instant::now() + NEGATIVE_TTL,
+            },
+            Err(_) => return,
+        };
+
+        let key = key(token);
+        let mut inner = self.inner();
+        inner.remove(&key);
+        while inner.entries.len() >= self.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(key, (entry, tick));
+        inner.recency.insert(tick, key);
+    }
+}
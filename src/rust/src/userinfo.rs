@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use crate::error::TapLockError;
+use crate::options::RuntimeOptions;
+use crate::token_cache::{Cached, TokenCache};
+use crate::validation::Clock;
+
+/// Remembers the claims of opaque access tokens, which can only be
+/// checked by asking the provider about them. They are fetched when
+/// the token is issued and trusted for `userinfo_ttl` (and never past
+/// the expiry of the token), so this is also how long a revoked token
+/// keeps working. Tokens are then refreshed, which fetches the claims
+/// again.
+#[derive(Clone)]
+pub(crate) struct UserInfoCache {
+    cache: Arc<TokenCache>,
+    clock: Clock,
+    ttl: u64,
+}
+
+impl UserInfoCache {
+    pub fn new(options: &RuntimeOptions) -> Self {
+        Self {
+            cache: Arc::new(TokenCache::new(options.token_cache_size)),
+            clock: options.validation.clock,
+            ttl: options.userinfo_ttl.as_secs(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Records the claims fetched for `token`.
+    pub fn insert(
+        &self,
+        token: &str,
+        result: &Result<serde_json::Value, TapLockError>,
+        expires_at: Option<u64>,
+    ) {
+        let valid_until = self.now().saturating_add(self.ttl);
+        let valid_until = expires_at.map_or(valid_until, |exp| exp.min(valid_until));
+        self.cache.insert(token, result, Some(valid_until));
+    }
+
+    /// The claims remembered for `token`. Tokens that are not
+    /// remembered are rejected with `UnknownToken`, so that they are
+    /// refreshed, rather than fetched on the thread decoding them.
+    pub fn get(&self, token: &str) -> Result<serde_json::Value, TapLockError> {
+        match self.cache.get(token, self.now()) {
+            Some(Cached::Valid(claims)) => Ok(claims),
+            Some(Cached::Rejected(err)) => Err(err),
+            None => Err(TapLockError::UnknownToken),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{Clock, TokenValidation};
+    use serde_json::json;
+    use std::time::Duration;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn cache(now: u64) -> UserInfoCache {
+        let options = RuntimeOptions {
+            validation: TokenValidation {
+                clock: Clock::Fixed(now),
+                ..TokenValidation::default()
+            },
+            userinfo_ttl: Duration::from_secs(300),
+            ..RuntimeOptions::default()
+        };
+        UserInfoCache::new(&options)
+    }
+
+    #[test]
+    fn keeps_the_claims_fetched_with_the_token() {
+        let claims = json!({ "sub": "1", "exp": NOW + 60 });
+        let userinfo = cache(NOW);
+        userinfo.insert("token", &Ok(claims.clone()), Some(NOW + 60));
+        assert_eq!(userinfo.get("token").unwrap(), claims);
+    }
+
+    #[test]
+    fn rejects_tokens_it_does_not_know() {
+        assert!(matches!(
+            cache(NOW).get("token"),
+            Err(TapLockError::UnknownToken)
+        ));
+    }
+}
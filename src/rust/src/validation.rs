@@ -0,0 +1,287 @@
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, Validation};
+use std::time::Duration;
+
+use crate::error::TapLockError;
+use crate::session;
+
+/// Where the current time comes from when checking tokens.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Clock {
+    System,
+    /// A fixed Unix time, for deterministic tests
+    Fixed(u64),
+}
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::System => session::now(),
+            Clock::Fixed(now) => *now,
+        }
+    }
+}
+
+/// How the claims of tokens are checked beyond their signature.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenValidation {
+    /// Seconds of clock skew tolerated on `exp`, `nbf` and the token age
+    pub leeway: u64,
+    pub validate_nbf: bool,
+    /// Maximum time since `auth_time` (or `iat` when absent)
+    pub max_age: Option<Duration>,
+    pub clock: Clock,
+    /// Accepted `aud` values, the client id when empty
+    pub audiences: Vec<String>,
+    /// Check the `azp` claim of tokens issued to several audiences
+    pub validate_azp: bool,
+}
+
+impl Default for TokenValidation {
+    // Same defaults as `jsonwebtoken`
+    fn default() -> Self {
+        Self {
+            leeway: 60,
+            validate_nbf: false,
+            max_age: None,
+            clock: Clock::System,
+            audiences: Vec::new(),
+            validate_azp: false,
+        }
+    }
+}
+
+fn claim(claims: &serde_json::Value, name: &str) -> Option<u64> {
+    claims
+        .get(name)
+        .and_then(|value| value.as_f64())
+        .map(|value| value as u64)
+}
+
+impl TokenValidation {
+    /// The `jsonwebtoken` validation checking the signature, algorithm
+    /// and audience. Time-based claims are left to [`Self::check_claims`]
+    /// so that they follow our clock.
+    pub fn jwt_validation(&self, algorithm: Algorithm, client_id: &str) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        if self.audiences.is_empty() {
+            validation.set_audience(&[client_id]);
+        } else {
+            validation.set_audience(&self.audiences);
+        }
+        validation.validate_exp = false;
+        validation.validate_nbf = false;
+        validation.leeway = self.leeway;
+        validation
+    }
+
+    /// Checks the claims `jsonwebtoken` does not: the time-based ones
+    /// and the authorized party.
+    pub fn check_claims(
+        &self,
+        claims: &serde_json::Value,
+        client_id: &str,
+    ) -> Result<(), TapLockError> {
+        self.check_times(claims)?;
+        if self.validate_azp {
+            self.check_azp(claims, client_id)?;
+        }
+        Ok(())
+    }
+
+    // OpenID Connect Core 3.1.3.7: tokens with several audiences must
+    // name the party they were issued to, which must be us (or one of
+    // the accepted audiences)
+    fn check_azp(&self, claims: &serde_json::Value, client_id: &str) -> Result<(), TapLockError> {
+        let audience_count = match claims.get("aud") {
+            Some(serde_json::Value::Array(aud)) => aud.len(),
+            _ => 1,
+        };
+        match claims.get("azp").and_then(|azp| azp.as_str()) {
+            Some(azp) if azp == client_id || self.audiences.iter().any(|aud| aud == azp) => Ok(()),
+            Some(azp) => Err(TapLockError::UnauthorizedParty(azp.to_string())),
+            None if audience_count > 1 => Err(TapLockError::UnauthorizedParty(String::new())),
+            None => Ok(()),
+        }
+    }
+
+    fn check_times(&self, claims: &serde_json::Value) -> Result<(), TapLockError> {
+        let now = self.clock.now();
+        let exp = claim(claims, "exp").ok_or(jsonwebtoken::errors::Error::from(
+            ErrorKind::MissingRequiredClaim("exp".to_string()),
+        ))?;
+        if exp.saturating_add(self.leeway) < now {
+            return Err(jsonwebtoken::errors::Error::from(ErrorKind::ExpiredSignature).into());
+        }
+        if self.validate_nbf {
+            if let Some(nbf) = claim(claims, "nbf") {
+                if nbf > now.saturating_add(self.leeway) {
+                    return Err(
+                        jsonwebtoken::errors::Error::from(ErrorKind::ImmatureSignature).into(),
+                    );
+                }
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            let issued_at = claim(claims, "auth_time")
+                .or_else(|| claim(claims, "iat"))
+                .ok_or(TapLockError::TokenTooOld)?;
+            if issued_at
+                .saturating_add(max_age.as_secs())
+                .saturating_add(self.leeway)
+                < now
+            {
+                return Err(TapLockError::TokenTooOld);
+            }
+        }
+        Ok(())
+    }
+
+    /// The time until which a token that passed [`Self::check_claims`]
+    /// keeps passing it.
+    pub fn valid_until(&self, claims: &serde_json::Value) -> Option<u64> {
+        let exp = claim(claims, "exp")?.saturating_add(self.leeway);
+        let max_age = self.max_age.and_then(|max_age| {
+            let issued_at = claim(claims, "auth_time").or_else(|| claim(claims, "iat"))?;
+            Some(
+                issued_at
+                    .saturating_add(max_age.as_secs())
+                    .saturating_add(self.leeway),
+            )
+        });
+        Some(max_age.map_or(exp, |max_age| exp.min(max_age)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn validation() -> TokenValidation {
+        TokenValidation {
+            leeway: 30,
+            clock: Clock::Fixed(NOW),
+            ..TokenValidation::default()
+        }
+    }
+
+    fn jwt_error(result: Result<(), TapLockError>) -> Option<ErrorKind> {
+        match result {
+            Err(TapLockError::JwtError(err)) => Some(err.into_kind()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn accepts_expired_tokens_within_the_leeway() {
+        let validation = validation();
+        assert!(validation
+            .check_claims(&json!({ "exp": NOW - 30 }), "client")
+            .is_ok());
+        assert_eq!(
+            jwt_error(validation.check_claims(&json!({ "exp": NOW - 31 }), "client")),
+            Some(ErrorKind::ExpiredSignature)
+        );
+    }
+
+    #[test]
+    fn requires_exp() {
+        assert_eq!(
+            jwt_error(validation().check_claims(&json!({}), "client")),
+            Some(ErrorKind::MissingRequiredClaim("exp".to_string()))
+        );
+    }
+
+    #[test]
+    fn checks_nbf_only_when_enabled() {
+        let claims = json!({ "exp": NOW + 3600, "nbf": NOW + 31 });
+        assert!(validation().check_claims(&claims, "client").is_ok());
+
+        let validation = TokenValidation {
+            validate_nbf: true,
+            ..validation()
+        };
+        assert_eq!(
+            jwt_error(validation.check_claims(&claims, "client")),
+            Some(ErrorKind::ImmatureSignature)
+        );
+        let claims = json!({ "exp": NOW + 3600, "nbf": NOW + 30 });
+        assert!(validation.check_claims(&claims, "client").is_ok());
+    }
+
+    #[test]
+    fn checks_the_age_of_the_login() {
+        let validation = TokenValidation {
+            max_age: Some(Duration::from_secs(600)),
+            ..validation()
+        };
+        let exp = NOW + 3600;
+        let ok = |claims| validation.check_claims(&claims, "client").is_ok();
+        assert!(ok(json!({ "exp": exp, "auth_time": NOW - 630 })));
+        assert!(!ok(json!({ "exp": exp, "auth_time": NOW - 631 })));
+        // `iat` is only used without `auth_time`
+        assert!(ok(json!({ "exp": exp, "iat": NOW - 630 })));
+        assert!(!ok(
+            json!({ "exp": exp, "iat": NOW, "auth_time": NOW - 631 })
+        ));
+        assert!(matches!(
+            validation.check_claims(&json!({ "exp": exp }), "client"),
+            Err(TapLockError::TokenTooOld)
+        ));
+    }
+
+    #[test]
+    fn tokens_are_valid_until_the_first_limit() {
+        let validation = validation();
+        assert_eq!(
+            validation.valid_until(&json!({ "exp": NOW + 100 })),
+            Some(NOW + 130)
+        );
+        assert_eq!(validation.valid_until(&json!({})), None);
+
+        let validation = TokenValidation {
+            max_age: Some(Duration::from_secs(600)),
+            ..validation
+        };
+        let claims = json!({ "exp": NOW + 3600, "auth_time": NOW });
+        assert_eq!(validation.valid_until(&claims), Some(NOW + 630));
+        let claims = json!({ "exp": NOW + 100, "auth_time": NOW });
+        assert_eq!(validation.valid_until(&claims), Some(NOW + 130));
+    }
+
+    #[test]
+    fn valid_until_matches_check_claims() {
+        let claims = json!({ "exp": NOW + 100, "auth_time": NOW - 200 });
+        let at = |now| TokenValidation {
+            clock: Clock::Fixed(now),
+            max_age: Some(Duration::from_secs(300)),
+            ..validation()
+        };
+        let until = at(NOW).valid_until(&claims).unwrap();
+        assert!(at(until).check_claims(&claims, "client").is_ok());
+        assert!(at(until + 1).check_claims(&claims, "client").is_err());
+    }
+
+    #[test]
+    fn checks_the_authorized_party() {
+        let validation = TokenValidation {
+            validate_azp: true,
+            ..validation()
+        };
+        let check = |claims| validation.check_claims(&claims, "client");
+        let exp = NOW + 3600;
+        assert!(check(json!({ "exp": exp, "aud": "client" })).is_ok());
+        assert!(check(json!({ "exp": exp, "aud": ["client", "api"], "azp": "client" })).is_ok());
+        assert!(matches!(
+            check(json!({ "exp": exp, "aud": ["client", "api"] })),
+            Err(TapLockError::UnauthorizedParty(_))
+        ));
+        assert!(matches!(
+            check(json!({ "exp": exp, "aud": "client", "azp": "other" })),
+            Err(TapLockError::UnauthorizedParty(_))
+        ));
+    }
+}